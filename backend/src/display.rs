@@ -1,31 +1,231 @@
 use futures::stream::unfold;
-use std::{pin::pin, sync::Arc};
+use std::{collections::HashSet, pin::pin, sync::Arc};
 use tokio_stream::StreamExt;
 use tracing::{error, warn};
+use uuid::Uuid;
 
-use crate::{GameState, messages::NGMessage, redis_wrapper::RedisWrapper, socket::Socket};
+use crate::{
+    GameState,
+    messages::{Capability, ClientKind, ErrorCode, NGMessage, NameRef},
+    prompts::PromptDeck,
+    socket::{ConnectionError, Receiver, Sender, Session, Socket, handshake},
+    store::{DuplicatePolicy, GameStore, GuessedName, RoomDefaults, RoomStore, RosterEntry},
+    token::TokenSigner,
+};
+
+/// Longest theme, in characters, that a host can set for a round.
+const MAX_THEME_LENGTH: usize = 200;
 
 enum Event {
-    Message(miette::Result<Option<NGMessage>>),
+    Message(Result<Option<NGMessage>, ConnectionError>),
     NewNameCount(usize),
     NameGuessed(usize),
     NameUnguessed(usize),
+    ScoresChanged,
+    PendingChanged,
+    NamesChanged,
+    NameSubmitted(String, Uuid),
+    NameUnsubmitted(Uuid),
+    PresenceChanged,
     StateChange(GameState),
+    Heartbeat,
+    Resync,
 }
 
-pub async fn handle_display(mut socket: Socket, redis_wrapper: Arc<RedisWrapper>) {
-    match redis_wrapper.state() {
-        GameState::Submitting(_) => socket
-            .send(NGMessage::NumNames(redis_wrapper.name_count()))
-            .await
-            .unwrap(),
+/// Whether only the host display may send the message.
+fn is_host_request(msg: &NGMessage) -> bool {
+    matches!(
+        msg,
+        NGMessage::RequestPlayingState
+            | NGMessage::GuessName(_)
+            | NGMessage::GuessNameBy(_, _)
+            | NGMessage::UnguessName(_)
+            | NGMessage::RequestRevealState
+            | NGMessage::RequestSubmittingState(_)
+            | NGMessage::DrawPrompt
+            | NGMessage::SetNameLimit(_)
+            | NGMessage::SetDuplicatePolicy(_)
+            | NGMessage::ApproveName(_)
+            | NGMessage::RejectName(_)
+            | NGMessage::RemoveName(_)
+            | NGMessage::SetPreview(_)
+    )
+}
+
+/// Sends the names submitted so far, for the host's preview, and remembers
+/// them in `previewed`. Submissions that were on their way in the meantime
+/// are skipped later, rather than shown twice.
+async fn send_preview(
+    socket_sender: &mut Sender,
+    room: &dyn GameStore,
+    previewed: &mut HashSet<Uuid>,
+) -> Result<(), ConnectionError> {
+    previewed.clear();
+    for (name, id) in room.submitted_names().await? {
+        previewed.insert(id);
+        socket_sender
+            .send(NGMessage::NameSubmitted(name, id))
+            .await?;
+    }
+    Ok(())
+}
+
+/// Sends everything a display needs to show the room as it is now.
+async fn send_snapshot(
+    socket_sender: &mut Sender,
+    room: &dyn GameStore,
+    moderator: bool,
+    preview: Option<&mut HashSet<Uuid>>,
+) -> Result<(), ConnectionError> {
+    match room.state() {
+        GameState::Submitting(epoch) => {
+            let theme = room.theme().await?;
+            socket_sender
+                .send(NGMessage::StateSubmitting(epoch, theme))
+                .await?;
+            socket_sender
+                .send(NGMessage::NumNames(room.name_count()))
+                .await?;
+            if moderator {
+                let pending = room.pending_names().await?;
+                socket_sender.send(NGMessage::PendingNames(pending)).await?
+            }
+            if let Some(previewed) = preview {
+                send_preview(socket_sender, room, previewed).await?
+            }
+        }
         GameState::Playing => {
-            let (names, guesses) = redis_wrapper.names_and_guesses().await.unwrap();
-            socket.send(NGMessage::Names(names, guesses)).await.unwrap()
+            let (names, guesses) = room.names_and_guesses().await?;
+            socket_sender.send(NGMessage::names(names, guesses)).await?
+        }
+        GameState::Revealing => {
+            let (names, guesses) = room.names_and_guesses().await?;
+            socket_sender.send(NGMessage::names(names, guesses)).await?;
+            let authors = room.authors().await?;
+            socket_sender.send(NGMessage::Authors(authors)).await?
+        }
+    }
+
+    let scores = room.scoreboard().await?;
+    socket_sender.send(NGMessage::Scoreboard(scores)).await
+}
+
+/// Sends who's connected, unless it's what the display was last sent.
+async fn send_roster(
+    socket_sender: &mut Sender,
+    room: &dyn GameStore,
+    last_roster: &mut Option<Vec<RosterEntry>>,
+) -> Result<(), ConnectionError> {
+    let roster = room.roster().await?;
+    if last_roster.as_ref() == Some(&roster) {
+        return Ok(());
+    }
+    socket_sender
+        .send(NGMessage::ConnectedPlayers(roster.len()))
+        .await?;
+    socket_sender
+        .send(NGMessage::Roster(roster.clone()))
+        .await?;
+    *last_roster = Some(roster);
+    Ok(())
+}
+
+/// Room a display connects to.
+pub enum DisplayRoom {
+    /// A room to create for the display, which hosts it, once the display has
+    /// said hello.
+    New(Arc<dyn RoomStore>),
+    /// An existing room, which the display controls if it's the host's.
+    Existing {
+        room: Arc<dyn GameStore>,
+        is_host: bool,
+    },
+}
+
+/// Handles a display connection. Only the host's display may control the
+/// room; the rest just mirror it.
+pub async fn handle_display(
+    socket: Socket,
+    room: DisplayRoom,
+    tokens: TokenSigner,
+    prompts: Arc<PromptDeck>,
+    defaults: RoomDefaults,
+) {
+    let (mut socket_sender, mut socket_receiver) = socket.split();
+    let session = match handshake(
+        &mut socket_sender,
+        &mut socket_receiver,
+        ClientKind::Display,
+    )
+    .await
+    {
+        Ok(session) => session,
+        Err(err) => return socket_sender.close(Err(err)).await,
+    };
+    // rooms are only created for clients that got this far, so that merely
+    // opening sockets doesn't use up room codes
+    let (room, is_host) = match room {
+        DisplayRoom::New(store) => match store.create_room().await {
+            Ok(room) => (room, true),
+            Err(err) => return socket_sender.close(Err(err.into())).await,
+        },
+        DisplayRoom::Existing { room, is_host } => (room, is_host),
+    };
+    let host_token = if is_host {
+        match room.host_nonce().await {
+            Ok(nonce) => Some(tokens.issue_host(room.code(), &nonce)),
+            Err(err) => return socket_sender.close(Err(err.into())).await,
         }
+    } else {
+        None
+    };
+    let result = serve_display(
+        &mut socket_sender,
+        socket_receiver,
+        session,
+        room.clone(),
+        host_token,
+        prompts,
+        defaults,
+    )
+    .await;
+    socket_sender.close(result).await;
+    // keeps whatever the host changed since the last heartbeat from outliving
+    // the room
+    if let Err(err) = room.touch().await {
+        error!("couldn't touch room {}: {err:?}", room.code());
     }
+}
 
-    let (mut socket_sender, socket_receiver) = socket.split();
+async fn serve_display(
+    socket_sender: &mut Sender,
+    socket_receiver: Receiver,
+    session: Session,
+    room: Arc<dyn GameStore>,
+    host_token: Option<String>,
+    prompts: Arc<PromptDeck>,
+    defaults: RoomDefaults,
+) -> Result<(), ConnectionError> {
+    room.touch().await?;
+    socket_sender
+        .send(NGMessage::JoinCode(room.code().clone()))
+        .await?;
+    let is_host = host_token.is_some();
+    // whether the display is the host's and can handle held names
+    let moderator = is_host && session.supports(Capability::Moderation);
+    let presence = session.supports(Capability::Presence);
+    if let Some(token) = host_token {
+        socket_sender.send(NGMessage::HostToken(token)).await?;
+    }
+    // whether the host display wants to see names as they're submitted
+    let mut preview = false;
+    // names the preview shows
+    let mut previewed = HashSet::new();
+    send_snapshot(socket_sender, room.as_ref(), moderator, None).await?;
+    let mut last_roster = None;
+    if presence {
+        send_roster(socket_sender, room.as_ref(), &mut last_roster).await?;
+    }
 
     let a = unfold(socket_receiver, async |mut socket_receiver| {
         Some((
@@ -33,72 +233,468 @@ pub async fn handle_display(mut socket: Socket, redis_wrapper: Arc<RedisWrapper>
             socket_receiver,
         ))
     });
-    let b = redis_wrapper.name_count_stream().map(Event::NewNameCount);
-    let c = redis_wrapper.guess_stream().map(Event::NameGuessed);
-    let d = redis_wrapper.unguess_stream().map(Event::NameUnguessed);
-    let e = redis_wrapper.state_change_stream().map(Event::StateChange);
-    let mut stream = pin!(a.merge(b).merge(c).merge(d).merge(e));
+    let b = room.name_count_stream().map(Event::NewNameCount);
+    let c = room.guess_stream().map(Event::NameGuessed);
+    let d = room.unguess_stream().map(Event::NameUnguessed);
+    let e = room.state_change_stream().map(Event::StateChange);
+    let f = room.scoreboard_stream().map(|()| Event::ScoresChanged);
+    let g = room.resync_stream().map(|()| Event::Resync);
+    let h = room.pending_stream().map(|()| Event::PendingChanged);
+    let i = room.names_changed_stream().map(|()| Event::NamesChanged);
+    let j = room
+        .submission_stream()
+        .map(|(name, id)| Event::NameSubmitted(name, id));
+    let k = room.unsubmission_stream().map(Event::NameUnsubmitted);
+    let l = room.presence_stream().map(|()| Event::PresenceChanged);
+    let m = socket_sender.heartbeats().map(|()| Event::Heartbeat);
+    let mut stream = pin!(
+        a.merge(b)
+            .merge(c)
+            .merge(d)
+            .merge(e)
+            .merge(f)
+            .merge(g)
+            .merge(h)
+            .merge(i)
+            .merge(j)
+            .merge(k)
+            .merge(l)
+            .merge(m)
+    );
 
     while let Some(event) = stream.next().await {
+        // joining, leaving and submitting all change the roster, and stale
+        // connections expire between heartbeats
+        let refresh_roster = presence
+            && matches!(
+                event,
+                Event::PresenceChanged
+                    | Event::NewNameCount(_)
+                    | Event::StateChange(_)
+                    | Event::Heartbeat
+                    | Event::Resync
+            );
         match event {
             Event::Message(msg) => {
                 let msg = match msg {
-                    Ok(Some(msg)) => msg,
-                    Ok(None) => break,
-                    Err(err) => {
-                        error!("error while receiving message from display: {err:?}");
-                        break;
+                    Err(ConnectionError::Throttled(msg_type)) => {
+                        socket_sender
+                            .send(NGMessage::error(
+                                ErrorCode::RateLimited,
+                                "you're sending messages too fast",
+                                msg_type,
+                            ))
+                            .await?;
+                        continue;
                     }
+                    msg => msg?,
+                };
+                let Some(msg) = msg else {
+                    break;
                 };
-                match msg {
-                    NGMessage::RequestPlayingState => {
-                        redis_wrapper.change_state_to_playing().await.unwrap();
+                let msg_type = msg.type_id();
+                let rejection = match msg {
+                    _ if !is_host && is_host_request(&msg) => {
+                        warn!("read-only display tried to control the room: {msg:?}");
+                        NGMessage::error(
+                            ErrorCode::Unauthorized,
+                            "only the host display can do that",
+                            msg_type,
+                        )
+                    }
+                    NGMessage::RequestPlayingState
+                        if matches!(room.state(), GameState::Submitting(_)) =>
+                    {
+                        let pending = room.pending_names().await?;
+                        if moderator && !pending.is_empty() {
+                            socket_sender
+                                .send(NGMessage::error(
+                                    ErrorCode::NamesPending,
+                                    "approve or reject the held names first",
+                                    msg_type,
+                                ))
+                                .await?;
+                            continue;
+                        }
+                        // a host that can't see held names can't approve them
+                        // either, so they stay out of the game
+                        for (_, id) in pending {
+                            room.reject_name(&id).await?;
+                        }
+                        let policy = room.effective_duplicate_policy(&defaults).await?;
+                        room.change_state_to_playing(policy == DuplicatePolicy::Merge)
+                            .await?;
+                        continue;
+                    }
+                    NGMessage::RequestRevealState if room.state() == GameState::Playing => {
+                        room.change_state_to_revealing().await?;
+                        continue;
+                    }
+                    NGMessage::RequestSubmittingState(theme) => {
+                        let theme = theme.as_deref().map(str::trim).filter(|t| !t.is_empty());
+                        if theme.is_some_and(|t| t.chars().count() > MAX_THEME_LENGTH) {
+                            NGMessage::error(
+                                ErrorCode::ThemeTooLong,
+                                format!("themes can be at most {MAX_THEME_LENGTH} characters long"),
+                                msg_type,
+                            )
+                        } else {
+                            room.change_state_to_submitting(theme).await?;
+                            continue;
+                        }
+                    }
+                    NGMessage::SetDuplicatePolicy(policy) => {
+                        room.set_duplicate_policy(policy).await?;
+                        continue;
+                    }
+                    NGMessage::ApproveName(id) => {
+                        if room.approve_name(&id).await? {
+                            continue;
+                        }
+                        NGMessage::error(
+                            ErrorCode::NoSuchName,
+                            "that name isn't waiting for approval",
+                            msg_type,
+                        )
+                    }
+                    NGMessage::RejectName(id) => {
+                        if room.reject_name(&id).await? {
+                            continue;
+                        }
+                        NGMessage::error(
+                            ErrorCode::NoSuchName,
+                            "that name isn't waiting for approval",
+                            msg_type,
+                        )
+                    }
+                    NGMessage::RemoveName(NameRef::Id(id))
+                        if matches!(room.state(), GameState::Submitting(_)) =>
+                    {
+                        if room.remove_name_as_host(&id).await? {
+                            continue;
+                        }
+                        NGMessage::error(ErrorCode::NoSuchName, "no such name", msg_type)
+                    }
+                    NGMessage::RemoveName(NameRef::Index(index))
+                        if room.state() == GameState::Playing =>
+                    {
+                        if room.remove_name_at(index).await? {
+                            continue;
+                        }
+                        NGMessage::error(ErrorCode::InvalidIndex, "no such name", msg_type)
+                    }
+                    NGMessage::RemoveName(_) => NGMessage::error(
+                        ErrorCode::WrongState,
+                        "names can be removed by id while submitting, or by index while playing",
+                        msg_type,
+                    ),
+                    NGMessage::SetPreview(enabled) => {
+                        if enabled && !preview && matches!(room.state(), GameState::Submitting(_)) {
+                            send_preview(socket_sender, room.as_ref(), &mut previewed).await?;
+                        }
+                        preview = enabled;
+                        continue;
+                    }
+                    NGMessage::SetNameLimit(limit) => {
+                        room.set_name_limit(limit).await?;
+                        continue;
+                    }
+                    NGMessage::DrawPrompt => match prompts.draw(room.as_ref()).await? {
+                        Some(prompt) => {
+                            room.change_state_to_submitting(Some(prompt)).await?;
+                            continue;
+                        }
+                        None => NGMessage::error(
+                            ErrorCode::NoPromptsLeft,
+                            "there are no prompts left to draw",
+                            msg_type,
+                        ),
+                    },
+                    NGMessage::RequestPlayingState | NGMessage::RequestRevealState => {
+                        NGMessage::error(
+                            ErrorCode::WrongState,
+                            "the room can't go to that state from here",
+                            msg_type,
+                        )
+                    }
+                    NGMessage::GuessName(_)
+                    | NGMessage::GuessNameBy(_, _)
+                    | NGMessage::UnguessName(_)
+                        if room.state() != GameState::Playing =>
+                    {
+                        NGMessage::error(
+                            ErrorCode::WrongState,
+                            "names can only be guessed while playing",
+                            msg_type,
+                        )
+                    }
+                    NGMessage::GuessName(index)
+                    | NGMessage::GuessNameBy(index, _)
+                    | NGMessage::UnguessName(index)
+                        if index >= room.name_count() =>
+                    {
+                        NGMessage::error(ErrorCode::InvalidIndex, "no such name", msg_type)
                     }
                     NGMessage::GuessName(index) => {
-                        redis_wrapper.guess_name(index).await.unwrap();
+                        // the host may have missed that a player just got the
+                        // name, whose point mustn't go to nobody; only naming
+                        // the guesser moves it
+                        room.guess_name(index, None, false).await?;
+                        continue;
                     }
-                    NGMessage::UnguessName(index) => {
-                        redis_wrapper.unguess_name(index).await.unwrap();
+                    NGMessage::GuessNameBy(index, player) => {
+                        if room.guess_name(index, Some(&player), true).await?
+                            != GuessedName::OwnName
+                        {
+                            continue;
+                        }
+                        warn!("display credited a player with guessing their own name");
+                        NGMessage::error(
+                            ErrorCode::Unauthorized,
+                            "players can't guess their own names",
+                            msg_type,
+                        )
                     }
-                    NGMessage::RequestSubmittingState => {
-                        redis_wrapper.change_state_to_submitting().await.unwrap();
+                    NGMessage::UnguessName(index) => {
+                        room.unguess_name(index).await?;
+                        continue;
                     }
                     _ => {
                         warn!("got unexpected message from display: {msg:?}");
-                        continue;
+                        NGMessage::error(
+                            ErrorCode::UnexpectedMessage,
+                            "displays can't send this message",
+                            msg_type,
+                        )
                     }
-                }
+                };
+                socket_sender.send(rejection).await?;
             }
-            Event::NewNameCount(num_names) => {
-                socket_sender
-                    .send(NGMessage::NumNames(num_names))
-                    .await
-                    .unwrap();
+            // clients take the count to mean the room is submitting, while
+            // during play it only changes as names are removed or merged
+            Event::NewNameCount(num_names) if matches!(room.state(), GameState::Submitting(_)) => {
+                socket_sender.send(NGMessage::NumNames(num_names)).await?;
             }
+            Event::NewNameCount(_) => {}
             Event::NameGuessed(index) => {
-                socket_sender
-                    .send(NGMessage::NameGuessed(index))
-                    .await
-                    .unwrap();
+                socket_sender.send(NGMessage::NameGuessed(index)).await?;
             }
             Event::NameUnguessed(index) => {
+                socket_sender.send(NGMessage::NameUnguessed(index)).await?;
+            }
+            Event::ScoresChanged => {
+                let scores = room.scoreboard().await?;
+                socket_sender.send(NGMessage::Scoreboard(scores)).await?;
+            }
+            Event::Resync => {
+                let previewed = preview.then_some(&mut previewed);
+                send_snapshot(socket_sender, room.as_ref(), moderator, previewed).await?;
+                last_roster = None;
+            }
+            // authors stay hidden, so the preview doesn't spoil the round
+            Event::NameSubmitted(name, id) if preview && previewed.insert(id) => {
                 socket_sender
-                    .send(NGMessage::NameUnguessed(index))
-                    .await
-                    .unwrap();
+                    .send(NGMessage::NameSubmitted(name, id))
+                    .await?;
+            }
+            Event::NameUnsubmitted(id) if preview && previewed.remove(&id) => {
+                socket_sender.send(NGMessage::NameUnsubmitted(id)).await?;
+            }
+            Event::NameSubmitted(_, _) | Event::NameUnsubmitted(_) => {}
+            // held names are unfiltered, so only the host gets to see them
+            Event::PendingChanged if moderator => {
+                let pending = room.pending_names().await?;
+                socket_sender.send(NGMessage::PendingNames(pending)).await?;
+            }
+            Event::PendingChanged => {}
+            Event::PresenceChanged => {}
+            Event::Heartbeat => {
+                socket_sender.heartbeat().await?;
+                room.touch().await?;
+            }
+            Event::NamesChanged => {
+                let (names, guesses) = room.names_and_guesses().await?;
+                socket_sender.send(NGMessage::names(names, guesses)).await?;
             }
             Event::StateChange(state) => match state {
-                GameState::Submitting(_) => {
-                    socket_sender.send(NGMessage::NumNames(0)).await.unwrap()
+                GameState::Submitting(epoch) => {
+                    previewed.clear();
+                    let theme = room.theme().await?;
+                    socket_sender
+                        .send(NGMessage::StateSubmitting(epoch, theme))
+                        .await?;
+                    socket_sender.send(NGMessage::NumNames(0)).await?
                 }
                 GameState::Playing => {
-                    let (names, guesses) = redis_wrapper.names_and_guesses().await.unwrap();
-                    socket_sender
-                        .send(NGMessage::Names(names, guesses))
-                        .await
-                        .unwrap()
+                    let (names, guesses) = room.names_and_guesses().await?;
+                    socket_sender.send(NGMessage::names(names, guesses)).await?
+                }
+                GameState::Revealing => {
+                    let authors = room.authors().await?;
+                    socket_sender.send(NGMessage::Authors(authors)).await?
                 }
             },
         }
+        if refresh_roster {
+            send_roster(socket_sender, room.as_ref(), &mut last_roster).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use secrecy::SecretString;
+    use tokio::task::JoinHandle;
+
+    use super::*;
+    use crate::{Player, PlayerId, memory_store::MemoryStore, socket::TestClient};
+
+    fn store() -> Arc<MemoryStore> {
+        Arc::new(MemoryStore::new(Duration::from_secs(60)))
+    }
+
+    fn tokens() -> TokenSigner {
+        TokenSigner::new(Some(SecretString::from("secret")))
+    }
+
+    fn connect(room: DisplayRoom) -> (TestClient, JoinHandle<()>) {
+        let (socket, client) = Socket::in_process();
+        let handler = tokio::spawn(handle_display(
+            socket,
+            room,
+            tokens(),
+            Arc::default(),
+            RoomDefaults::default(),
+        ));
+        (client, handler)
+    }
+
+    #[tokio::test]
+    async fn host_display_gets_a_room_after_saying_hello() {
+        let store = store();
+        let (mut client, handler) = connect(DisplayRoom::New(store.clone()));
+        client.hello(ClientKind::Display, &[]);
+        assert!(matches!(
+            client.recv().await,
+            Some(NGMessage::Welcome { .. })
+        ));
+        let Some(NGMessage::JoinCode(code)) = client.recv().await else {
+            panic!("display didn't get a join code");
+        };
+        let Some(NGMessage::HostToken(token)) = client.recv().await else {
+            panic!("host display didn't get a host token");
+        };
+        let room = store.room(code).await.unwrap().unwrap();
+        let nonce = room.host_nonce().await.unwrap();
+        assert!(tokens().verify_host(room.code(), &nonce, &token));
+
+        drop(client);
+        handler.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn only_the_host_display_controls_the_room() {
+        let room = store().create_room().await.unwrap();
+        let (mut client, handler) = connect(DisplayRoom::Existing {
+            room: room.clone(),
+            is_host: false,
+        });
+        client.hello(ClientKind::Display, &[]);
+        client.send(NGMessage::RequestPlayingState);
+        let code = client
+            .find(|message| match message {
+                NGMessage::HostToken(_) => panic!("viewer display got a host token"),
+                NGMessage::Error { code, .. } => Some(code),
+                _ => None,
+            })
+            .await;
+        assert_eq!(code, ErrorCode::Unauthorized);
+        assert!(matches!(room.state(), GameState::Submitting(_)));
+
+        drop(client);
+        handler.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn unattributed_guesses_leave_points_alone() {
+        let room = store().create_room().await.unwrap();
+        let ann = Player::new(PlayerId::new(), Some("Ann".to_string()));
+        let bob = Player::new(PlayerId::new(), Some("Bob".to_string()));
+        for player in [&ann, &bob] {
+            room.set_nickname(player).await.unwrap();
+        }
+        room.add_name("Ada", &ann.id, None, false, false)
+            .await
+            .unwrap();
+        room.change_state_to_playing(false).await.unwrap();
+        assert_eq!(
+            room.guess_name(0, Some(&bob.id), false).await.unwrap(),
+            GuessedName::Guessed
+        );
+
+        let (mut client, handler) = connect(DisplayRoom::Existing {
+            room: room.clone(),
+            is_host: true,
+        });
+        client.hello(ClientKind::Display, &[]);
+        client.send(NGMessage::GuessName(0));
+        // an invalid guess is answered, so the one before it has been handled
+        client.send(NGMessage::GuessName(1));
+        client
+            .find(|message| match message {
+                NGMessage::Error { code, .. } => Some(code),
+                _ => None,
+            })
+            .await;
+        let scores = room.scoreboard().await.unwrap();
+        let score = scores.iter().find(|score| score.player == bob.id).unwrap();
+        assert_eq!(score.round_points, 1);
+
+        drop(client);
+        handler.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn preview_shows_each_name_once() {
+        let room = store().create_room().await.unwrap();
+        let ann = Player::new(PlayerId::new(), Some("Ann".to_string()));
+        room.set_nickname(&ann).await.unwrap();
+        let (mut client, handler) = connect(DisplayRoom::Existing {
+            room: room.clone(),
+            is_host: true,
+        });
+        client.hello(ClientKind::Display, &[]);
+        client
+            .find(|message| matches!(message, NGMessage::NumNames(_)).then_some(()))
+            .await;
+
+        // some of the submissions are still on their way when the preview is
+        // sent
+        let names: Vec<_> = (0..20).map(|i| format!("Ada {i}")).collect();
+        for name in &names {
+            room.add_name(name, &ann.id, None, false, false)
+                .await
+                .unwrap();
+        }
+        client.send(NGMessage::SetPreview(true));
+        let mut shown = Vec::new();
+        // by the second answer, every queued submission has been handled
+        for _ in 0..2 {
+            client.send(NGMessage::GuessName(0));
+            loop {
+                match client.recv().await.unwrap() {
+                    NGMessage::NameSubmitted(name, _) => shown.push(name),
+                    NGMessage::Error { .. } => break,
+                    _ => {}
+                }
+            }
+        }
+        assert_eq!(shown, names);
+
+        drop(client);
+        handler.await.unwrap();
     }
 }
@@ -0,0 +1,157 @@
+use std::{collections::HashSet, path::Path};
+
+use miette::{Context, bail};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::{names::dedup_key, settings::load_file};
+
+/// What happens to a submitted name containing a listed word.
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterMode {
+    /// Refuse the name.
+    #[default]
+    Reject,
+    /// Replace the listed words with asterisks.
+    Mask,
+    /// Keep the name out of the game until the host approves it, or for good
+    /// if the host display can't moderate.
+    Hold,
+}
+
+/// Outcome of checking a name against the filter.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allowed(String),
+    Rejected,
+    Held(String),
+}
+
+#[derive(serde::Deserialize)]
+struct WordList {
+    words: Vec<String>,
+}
+
+/// Word list that submitted names are checked against, so that game nights
+/// with kids stay clean. Entries may be single words or phrases, and match
+/// whole words, ignoring case, accents and what separates the words of a
+/// phrase.
+#[derive(Debug, Default)]
+pub struct ContentFilter {
+    /// Listed entries, each split into its words.
+    phrases: HashSet<Vec<String>>,
+    /// Number of words in the longest entry.
+    longest: usize,
+    mode: FilterMode,
+}
+
+impl ContentFilter {
+    /// Loads the filter from a file with a `words` list.
+    pub fn load(path: &Path, mode: FilterMode) -> miette::Result<Self> {
+        let list: WordList = load_file(path).wrap_err("load content filter")?;
+        Self::new(&list.words, mode)
+            .wrap_err_with(|| format!("invalid content filter in {}", path.display()))
+    }
+
+    fn new(entries: &[String], mode: FilterMode) -> miette::Result<Self> {
+        let mut phrases = HashSet::new();
+        for entry in entries {
+            let phrase: Vec<_> = entry.unicode_words().map(dedup_key).collect();
+            if phrase.is_empty() {
+                bail!("entry {entry:?} has no words in it");
+            }
+            phrases.insert(phrase);
+        }
+        let longest = phrases.iter().map(Vec::len).max().unwrap_or(0);
+        Ok(Self {
+            phrases,
+            longest,
+            mode,
+        })
+    }
+
+    /// Splits a name at word boundaries, flagging the segments that are part
+    /// of a listed entry.
+    fn segments<'a>(&self, name: &'a str) -> Vec<(&'a str, bool)> {
+        let mut segments: Vec<_> = name.split_word_bounds().map(|s| (s, false)).collect();
+        // the segments that are words, rather than spaces or punctuation
+        let words: Vec<_> = (0..segments.len())
+            .filter(|&i| segments[i].0.chars().any(char::is_alphanumeric))
+            .collect();
+        let keys: Vec<_> = words.iter().map(|&i| dedup_key(segments[i].0)).collect();
+        for start in 0..keys.len() {
+            for end in start + 1..=keys.len().min(start + self.longest) {
+                if self.phrases.contains(&keys[start..end]) {
+                    for &i in &words[start..end] {
+                        segments[i].1 = true;
+                    }
+                }
+            }
+        }
+        segments
+    }
+
+    pub fn check(&self, name: String) -> Verdict {
+        let segments = self.segments(&name);
+        if !segments.iter().any(|&(_, listed)| listed) {
+            return Verdict::Allowed(name);
+        }
+        match self.mode {
+            FilterMode::Reject => Verdict::Rejected,
+            FilterMode::Mask => Verdict::Allowed(mask(&segments)),
+            FilterMode::Hold => Verdict::Held(name),
+        }
+    }
+}
+
+/// Replaces every grapheme of each listed word with an asterisk.
+fn mask(segments: &[(&str, bool)]) -> String {
+    segments
+        .iter()
+        .map(|&(segment, listed)| {
+            if listed {
+                "*".repeat(segment.graphemes(true).count())
+            } else {
+                segment.to_string()
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(entries: &[&str], mode: FilterMode) -> ContentFilter {
+        let entries: Vec<_> = entries.iter().map(|entry| entry.to_string()).collect();
+        ContentFilter::new(&entries, mode).unwrap()
+    }
+
+    #[test]
+    fn words_match_whole() {
+        let filter = filter(&["heck"], FilterMode::Reject);
+        assert_eq!(filter.check("What the HÉCK".into()), Verdict::Rejected);
+        assert_eq!(
+            filter.check("Checkers".into()),
+            Verdict::Allowed("Checkers".into())
+        );
+    }
+
+    #[test]
+    fn phrases_match_across_separators() {
+        let filter = filter(&["  darn it "], FilterMode::Mask);
+        assert_eq!(
+            filter.check("Oh darn-it all".into()),
+            Verdict::Allowed("Oh ****-** all".into())
+        );
+        assert_eq!(
+            filter.check("darn, I forgot it".into()),
+            Verdict::Allowed("darn, I forgot it".into())
+        );
+    }
+
+    #[test]
+    fn entries_without_words_are_refused() {
+        assert!(ContentFilter::new(&["?!".to_string()], FilterMode::Reject).is_err());
+    }
+}
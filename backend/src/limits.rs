@@ -0,0 +1,422 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use axum::http::{HeaderMap, header::FORWARDED};
+
+/// How fast clients may send messages, and how many sockets they may open.
+/// Families often share one address, so the per-address limits are looser.
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RateLimits {
+    /// Messages per second a socket may send on average.
+    pub socket_rate: f64,
+    /// Messages a socket may send at once before being throttled.
+    pub socket_burst: u32,
+    /// Messages per second all sockets from one address may send on average.
+    pub ip_rate: f64,
+    pub ip_burst: u32,
+    /// Sockets that may be open from one address at once.
+    pub max_sockets_per_ip: usize,
+    /// Throttled messages in a row after which a socket is closed.
+    pub max_strikes: u32,
+    /// Rooms per hour one address may create on average.
+    pub rooms_per_hour: f64,
+    /// Rooms one address may create at once.
+    pub room_burst: u32,
+    /// Reverse proxies whose `Forwarded` or `X-Forwarded-For` headers are
+    /// believed. Without any, every client behind a proxy shares its address.
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            socket_rate: 10.0,
+            socket_burst: 20,
+            ip_rate: 100.0,
+            ip_burst: 200,
+            max_sockets_per_ip: 32,
+            max_strikes: 20,
+            rooms_per_hour: 30.0,
+            room_burst: 5,
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate,
+            burst: burst.into(),
+            tokens: burst.into(),
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let refill = now.duration_since(self.updated).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.burst);
+        self.updated = now;
+    }
+
+    /// Whether the bucket is back to where a new one would start.
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.burst
+    }
+
+    fn has_token(&mut self) -> bool {
+        self.refill();
+        self.tokens >= 1.0
+    }
+
+    fn take(&mut self) -> bool {
+        if !self.has_token() {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[derive(Debug)]
+struct Address {
+    sockets: usize,
+    bucket: TokenBucket,
+}
+
+/// Tracks open sockets and message rates per remote address.
+#[derive(Debug)]
+pub struct Limiter {
+    limits: RateLimits,
+    addresses: Mutex<HashMap<IpAddr, Address>>,
+    /// Rooms created per address. Unlike sockets, these outlive the
+    /// connection, or reconnecting would reset them.
+    rooms: Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+
+impl Limiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            addresses: Mutex::new(HashMap::new()),
+            rooms: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The address the limits apply to for a client connecting from `peer`.
+    /// That's `peer` itself, unless it's a trusted proxy that says whom it's
+    /// forwarding for.
+    pub fn client_ip(&self, peer: SocketAddr, headers: &HeaderMap) -> IpAddr {
+        let trusted = &self.limits.trusted_proxies;
+        let mut ip = peer.ip();
+        if !trusted.contains(&ip) {
+            return ip;
+        }
+        // each proxy appends whom it got the request from, so walk back from
+        // the last hop until one isn't ours; anything before it could be forged
+        for hop in forwarded_for(headers).into_iter().rev() {
+            let Some(hop) = hop else { break };
+            ip = hop;
+            if !trusted.contains(&ip) {
+                break;
+            }
+        }
+        ip
+    }
+
+    /// Whether `ip` may create another room right now.
+    pub fn create_room(&self, ip: IpAddr) -> bool {
+        let mut rooms = self.rooms.lock().unwrap();
+        // a full bucket is as good as none, so the map doesn't grow forever
+        rooms.retain(|_, bucket| !bucket.is_full());
+        rooms
+            .entry(ip)
+            .or_insert_with(|| {
+                TokenBucket::new(self.limits.rooms_per_hour / 3600.0, self.limits.room_burst)
+            })
+            .take()
+    }
+
+    /// Registers a new socket from `ip`, or returns `None` if the address
+    /// already has as many open as it may.
+    pub fn connect(self: &Arc<Self>, ip: IpAddr) -> Option<SocketLimits> {
+        let mut addresses = self.addresses.lock().unwrap();
+        let address = addresses.entry(ip).or_insert_with(|| Address {
+            sockets: 0,
+            bucket: TokenBucket::new(self.limits.ip_rate, self.limits.ip_burst),
+        });
+        if address.sockets >= self.limits.max_sockets_per_ip {
+            return None;
+        }
+        address.sockets += 1;
+        Some(SocketLimits {
+            limiter: self.clone(),
+            ip,
+            bucket: TokenBucket::new(self.limits.socket_rate, self.limits.socket_burst),
+        })
+    }
+}
+
+/// Rate limit of a single socket, which also counts towards its address's
+/// limits until dropped.
+#[derive(Debug)]
+pub struct SocketLimits {
+    limiter: Arc<Limiter>,
+    ip: IpAddr,
+    bucket: TokenBucket,
+}
+
+impl SocketLimits {
+    /// Whether the socket may send another message right now.
+    pub fn allow(&mut self) -> bool {
+        let mut addresses = self.limiter.addresses.lock().unwrap();
+        let mut address = addresses.get_mut(&self.ip);
+        // a throttled message costs neither bucket, or a busy address would
+        // drain its quiet sockets' buckets too
+        if !self.bucket.has_token() || address.as_mut().is_some_and(|a| !a.bucket.has_token()) {
+            return false;
+        }
+        self.bucket.take();
+        if let Some(address) = address {
+            address.bucket.take();
+        }
+        true
+    }
+
+    pub fn max_strikes(&self) -> u32 {
+        self.limiter.limits.max_strikes
+    }
+}
+
+impl Drop for SocketLimits {
+    fn drop(&mut self) {
+        let mut addresses = self.limiter.addresses.lock().unwrap();
+        if let Some(address) = addresses.get_mut(&self.ip) {
+            address.sockets -= 1;
+            // forget the address once it's gone, so the map doesn't grow forever
+            if address.sockets == 0 {
+                addresses.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// The hops listed by `Forwarded`, or by `X-Forwarded-For` if there's none,
+/// from the client to the last proxy. Hops that aren't addresses, such as
+/// `unknown` or obfuscated ones, are `None`.
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect::<Vec<_>>()
+    };
+    let forwarded = values(FORWARDED.as_str());
+    if !forwarded.is_empty() {
+        return forwarded
+            .into_iter()
+            .map(|element| {
+                element
+                    .split(';')
+                    .find_map(|pair| {
+                        let (key, value) = pair.trim().split_once('=')?;
+                        key.eq_ignore_ascii_case("for").then_some(value)
+                    })
+                    .and_then(parse_node)
+            })
+            .collect();
+    }
+    values("x-forwarded-for")
+        .into_iter()
+        .map(parse_node)
+        .collect()
+}
+
+/// Parses a hop such as `192.0.2.1`, `"192.0.2.1:4711"` or `"[2001:db8::1]"`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    node.parse()
+        .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| node.trim_start_matches('[').trim_end_matches(']').parse())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    const ANN: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const BOB: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+    const PROXY: IpAddr = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+
+    /// Limits that never refill, so tests don't depend on timing.
+    fn still_limits() -> RateLimits {
+        RateLimits {
+            socket_rate: 0.0,
+            ip_rate: 0.0,
+            ..RateLimits::default()
+        }
+    }
+
+    fn peer(ip: IpAddr) -> SocketAddr {
+        SocketAddr::new(ip, 4711)
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for &(name, value) in pairs {
+            headers.append(name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn buckets_refill_up_to_their_burst() {
+        let mut bucket = TokenBucket::new(1.0, 2);
+        assert!(bucket.take());
+        assert!(bucket.take());
+        assert!(!bucket.take());
+        bucket.updated -= Duration::from_secs(10);
+        assert!(bucket.take());
+        assert!(bucket.take());
+        assert!(!bucket.take());
+    }
+
+    #[test]
+    fn sockets_are_throttled_after_their_burst() {
+        let limiter = Arc::new(Limiter::new(RateLimits {
+            socket_burst: 3,
+            ..still_limits()
+        }));
+        let mut socket = limiter.connect(ANN).unwrap();
+        assert!((0..3).all(|_| socket.allow()));
+        assert!(!socket.allow());
+        // the address still has plenty left for other sockets
+        assert!(limiter.connect(ANN).unwrap().allow());
+    }
+
+    #[test]
+    fn sockets_share_their_address_limit() {
+        let limiter = Arc::new(Limiter::new(RateLimits {
+            ip_burst: 3,
+            ..still_limits()
+        }));
+        let mut first = limiter.connect(ANN).unwrap();
+        let mut second = limiter.connect(ANN).unwrap();
+        assert!(first.allow());
+        assert!(first.allow());
+        assert!(second.allow());
+        assert!(!second.allow());
+        assert!(!first.allow());
+        assert!(limiter.connect(BOB).unwrap().allow());
+    }
+
+    #[test]
+    fn throttled_messages_cost_the_socket_nothing() {
+        let limiter = Arc::new(Limiter::new(RateLimits {
+            socket_burst: 5,
+            ip_burst: 1,
+            ..still_limits()
+        }));
+        let mut socket = limiter.connect(ANN).unwrap();
+        assert!(socket.allow());
+        assert!(!socket.allow());
+        assert!(!socket.allow());
+        assert_eq!(socket.bucket.tokens, 4.0);
+    }
+
+    #[test]
+    fn open_sockets_are_limited_per_address() {
+        let limiter = Arc::new(Limiter::new(RateLimits {
+            max_sockets_per_ip: 2,
+            ..RateLimits::default()
+        }));
+        let first = limiter.connect(ANN).unwrap();
+        let _second = limiter.connect(ANN).unwrap();
+        assert!(limiter.connect(ANN).is_none());
+        assert!(limiter.connect(BOB).is_some());
+        drop(first);
+        assert!(limiter.connect(ANN).is_some());
+    }
+
+    #[test]
+    fn untrusted_peers_cant_claim_another_address() {
+        let limiter = Limiter::new(RateLimits::default());
+        let headers = headers(&[("x-forwarded-for", "192.0.2.1")]);
+        assert_eq!(limiter.client_ip(peer(BOB), &headers), BOB);
+    }
+
+    #[test]
+    fn trusted_proxies_say_whom_they_forward_for() {
+        let limiter = Limiter::new(RateLimits {
+            trusted_proxies: vec![PROXY],
+            ..RateLimits::default()
+        });
+        let ip = |pairs| limiter.client_ip(peer(PROXY), &headers(pairs));
+        assert_eq!(ip(&[]), PROXY);
+        assert_eq!(ip(&[("x-forwarded-for", "192.0.2.1")]), ANN);
+        // only the hop our proxy added counts, the client may have made up the rest
+        assert_eq!(ip(&[("x-forwarded-for", "192.0.2.2, 192.0.2.1")]), ANN);
+        assert_eq!(
+            ip(&[
+                ("x-forwarded-for", "192.0.2.2"),
+                ("x-forwarded-for", "192.0.2.1")
+            ]),
+            ANN
+        );
+        assert_eq!(ip(&[("x-forwarded-for", "192.0.2.1, 198.51.100.1")]), ANN);
+        assert_eq!(ip(&[("x-forwarded-for", "unknown")]), PROXY);
+        assert_eq!(
+            ip(&[(
+                "forwarded",
+                "for=192.0.2.2, for=\"192.0.2.1:4711\";proto=https"
+            )]),
+            ANN
+        );
+        assert_eq!(
+            ip(&[("forwarded", "For=\"[2001:db8::1]:4711\"")]),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+        // Forwarded wins, since proxies that send both put the same hops in it
+        assert_eq!(
+            ip(&[
+                ("forwarded", "for=192.0.2.1"),
+                ("x-forwarded-for", "192.0.2.2")
+            ]),
+            ANN
+        );
+    }
+
+    #[test]
+    fn room_creation_is_limited_per_address() {
+        let limiter = Limiter::new(RateLimits {
+            room_burst: 2,
+            ..RateLimits::default()
+        });
+        assert!(limiter.create_room(ANN));
+        assert!(limiter.create_room(ANN));
+        assert!(!limiter.create_room(ANN));
+        assert!(limiter.create_room(BOB));
+    }
+}
@@ -1,27 +1,52 @@
-use std::sync::Arc;
+use std::{fmt, net::SocketAddr, sync::Arc, time::Duration};
 
 use axum::{
     Router,
-    extract::{State, WebSocketUpgrade},
-    response::IntoResponse,
-    routing::any,
+    extract::{ConnectInfo, Path, Query, State, WebSocketUpgrade},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{any, get},
 };
-use miette::IntoDiagnostic;
+use miette::{IntoDiagnostic, bail};
+use rand::{Rng, rng};
+use secrecy::{ExposeSecret, SecretString};
+use subtle::ConstantTimeEq;
 use tower_http::{
     services::{ServeDir, ServeFile},
     trace::TraceLayer,
 };
-use tracing::info;
-use tracing_subscriber::{filter::EnvFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt};
+use tracing::{error, info};
+use tracing_subscriber::{filter::EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
+use uuid::Uuid;
 
-use crate::{redis_wrapper::RedisWrapper, settings::get_settings, socket::Socket};
+use crate::{
+    display::DisplayRoom,
+    filter::ContentFilter,
+    limits::Limiter,
+    memory_store::MemoryStore,
+    names::normalize_nickname,
+    prompts::PromptDeck,
+    redis_wrapper::RedisWrapper,
+    settings::{StoreKind, get_settings},
+    socket::{Heartbeat, Socket},
+    store::{GameStore, RoomDefaults, RoomStore},
+    token::TokenSigner,
+};
 
 mod display;
+mod filter;
+mod limits;
+mod memory_store;
 mod messages;
+mod names;
 mod player;
+mod prompts;
 mod redis_wrapper;
+mod schema;
 mod settings;
 mod socket;
+mod store;
+mod token;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
 pub struct Epoch(u32);
@@ -30,35 +55,279 @@ pub struct Epoch(u32);
 enum GameState {
     Submitting(Epoch),
     Playing,
+    Revealing,
 }
 
 impl GameState {
     const SUBMITTING: &'static str = "submitting";
     const PLAYING: &'static str = "playing";
+    const REVEALING: &'static str = "revealing";
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct PlayerId(Uuid);
+
+impl PlayerId {
+    fn new() -> Self {
+        Self(Uuid::new_v4())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Player {
+    id: PlayerId,
+    nickname: String,
+}
+
+impl Player {
+    const DEFAULT_NICKNAME: &'static str = "Anonymous";
+
+    fn new(id: PlayerId, nickname: Option<String>) -> Self {
+        let nickname = nickname
+            .as_deref()
+            .and_then(normalize_nickname)
+            .unwrap_or_else(|| Self::DEFAULT_NICKNAME.to_string());
+        Self { id, nickname }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct PlayerParams {
+    nickname: Option<String>,
+    /// Token from an earlier connection to the same room, to keep the same
+    /// identity.
+    token: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct DisplayParams {
+    /// Host token from an earlier connection to the same room.
+    token: Option<String>,
+    secret: Option<String>,
+}
+
+#[derive(Clone)]
+struct AppState {
+    store: Arc<dyn RoomStore>,
+    tokens: TokenSigner,
+    host_secret: Option<SecretString>,
+    prompts: Arc<PromptDeck>,
+    filter: Arc<ContentFilter>,
+    limiter: Arc<Limiter>,
+    heartbeat: Heartbeat,
+    room_defaults: RoomDefaults,
+    max_message_size: usize,
+}
+
+impl AppState {
+    fn knows_host_secret(&self, params: &DisplayParams) -> bool {
+        match (&self.host_secret, &params.secret) {
+            (Some(expected), Some(secret)) => {
+                bool::from(expected.expose_secret().as_bytes().ct_eq(secret.as_bytes()))
+            }
+            _ => false,
+        }
+    }
+
+    /// Keeps clients from making us buffer huge messages.
+    fn limit_sizes(&self, ws: WebSocketUpgrade) -> WebSocketUpgrade {
+        ws.max_message_size(self.max_message_size)
+            .max_frame_size(self.max_message_size)
+    }
+}
+
+/// Short join code identifying a room, e.g. `HJKR`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct RoomCode(String);
+
+impl RoomCode {
+    const ALPHABET: &'static [u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+    const LEN: usize = 4;
+
+    pub fn generate() -> Self {
+        let mut rng = rng();
+        let code = (0..Self::LEN)
+            .map(|_| Self::ALPHABET[rng.random_range(0..Self::ALPHABET.len())] as char)
+            .collect();
+        Self(code)
+    }
+}
+
+impl TryFrom<String> for RoomCode {
+    type Error = miette::Report;
+
+    fn try_from(mut code: String) -> miette::Result<Self> {
+        code.make_ascii_uppercase();
+        if code.len() != Self::LEN || !code.bytes().all(|b| Self::ALPHABET.contains(&b)) {
+            bail!("invalid room code: {code:?}");
+        }
+        Ok(Self(code))
+    }
+}
+
+impl From<RoomCode> for String {
+    fn from(code: RoomCode) -> Self {
+        code.0
+    }
+}
+
+impl fmt::Display for RoomCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+async fn find_room(store: &dyn RoomStore, code: RoomCode) -> Result<Arc<dyn GameStore>, Response> {
+    match store.room(code).await {
+        Ok(Some(room)) => Ok(room),
+        Ok(None) => Err(StatusCode::NOT_FOUND.into_response()),
+        Err(err) => {
+            error!("error while looking up room: {err:?}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+/// Tells clients whether a room exists, so that they can tell a room that's
+/// gone from a server that's unreachable for a moment.
+async fn room_status(Path(code): Path<String>, State(state): State<AppState>) -> Response {
+    let Ok(code) = RoomCode::try_from(code) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    match find_room(state.store.as_ref(), code).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(response) => response,
+    }
 }
 
 async fn player_upgrader(
     ws: WebSocketUpgrade,
-    State(redis_wrapper): State<Arc<RedisWrapper>>,
-) -> impl IntoResponse {
+    Path(code): Path<RoomCode>,
+    Query(params): Query<PlayerParams>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Response {
+    let ip = state.limiter.client_ip(addr, &headers);
+    let Some(limits) = state.limiter.connect(ip) else {
+        return StatusCode::TOO_MANY_REQUESTS.into_response();
+    };
+    let room = match find_room(state.store.as_ref(), code).await {
+        Ok(room) => room,
+        Err(response) => return response,
+    };
+    let ws = state.limit_sizes(ws);
+    let id = params
+        .token
+        .and_then(|token| state.tokens.verify(room.code(), &token));
+    // a returning player keeps their nickname unless they pick a new one
+    let nickname = match (params.nickname, id) {
+        (Some(nickname), _) => Some(nickname),
+        (None, Some(id)) => match room.nickname(&id).await {
+            Ok(nickname) => nickname,
+            Err(err) => {
+                error!("error while looking up nickname: {err:?}");
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        },
+        (None, None) => None,
+    };
+    let player = Player::new(id.unwrap_or_else(PlayerId::new), nickname);
+    let token = state.tokens.issue(room.code(), &player.id);
     ws.on_upgrade(async move |socket| {
-        player::handle_player(Socket::new(socket), redis_wrapper.clone()).await;
+        player::handle_player(
+            Socket::new(socket, limits, state.heartbeat),
+            room,
+            player,
+            token,
+            state.filter,
+            state.room_defaults,
+        )
+        .await;
+    })
+}
+
+async fn new_display_upgrader(
+    ws: WebSocketUpgrade,
+    Query(params): Query<DisplayParams>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Response {
+    if state.host_secret.is_some() && !state.knows_host_secret(&params) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let ip = state.limiter.client_ip(addr, &headers);
+    if !state.limiter.create_room(ip) {
+        return StatusCode::TOO_MANY_REQUESTS.into_response();
+    }
+    let Some(limits) = state.limiter.connect(ip) else {
+        return StatusCode::TOO_MANY_REQUESTS.into_response();
+    };
+    let ws = state.limit_sizes(ws);
+    ws.on_upgrade(async move |socket| {
+        display::handle_display(
+            Socket::new(socket, limits, state.heartbeat),
+            DisplayRoom::New(state.store),
+            state.tokens,
+            state.prompts,
+            state.room_defaults,
+        )
+        .await;
     })
 }
 
 async fn display_upgrader(
     ws: WebSocketUpgrade,
-    State(redis_wrapper): State<Arc<RedisWrapper>>,
-) -> impl IntoResponse {
+    Path(code): Path<RoomCode>,
+    Query(params): Query<DisplayParams>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Response {
+    let ip = state.limiter.client_ip(addr, &headers);
+    let Some(limits) = state.limiter.connect(ip) else {
+        return StatusCode::TOO_MANY_REQUESTS.into_response();
+    };
+    let room = match find_room(state.store.as_ref(), code).await {
+        Ok(room) => room,
+        Err(response) => return response,
+    };
+    // any other display can still watch the room, but not control it
+    let is_host = match params.token {
+        _ if state.knows_host_secret(&params) => true,
+        Some(token) => match room.host_nonce().await {
+            Ok(nonce) => state.tokens.verify_host(room.code(), &nonce, &token),
+            Err(err) => {
+                error!("couldn't get host nonce of room {}: {err:?}", room.code());
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        },
+        None => false,
+    };
+    let ws = state.limit_sizes(ws);
     ws.on_upgrade(async move |socket| {
-        display::handle_display(Socket::new(socket), redis_wrapper.clone()).await;
+        display::handle_display(
+            Socket::new(socket, limits, state.heartbeat),
+            DisplayRoom::Existing { room, is_host },
+            state.tokens,
+            state.prompts,
+            state.room_defaults,
+        )
+        .await;
     })
 }
 
 #[tokio::main]
 async fn main() -> miette::Result<()> {
+    if std::env::args().nth(1).as_deref() == Some("--emit-ts-schema") {
+        print!("{}", messages::ts_schema());
+        return Ok(());
+    }
+
     tracing_subscriber::registry()
-        .with(fmt::layer())
+        .with(tracing_subscriber::fmt::layer())
         .with(
             EnvFilter::from_default_env()
                 .add_directive("tokio_http=debug".parse().unwrap())
@@ -70,11 +339,48 @@ async fn main() -> miette::Result<()> {
         .await
         .into_diagnostic()??;
 
+    let prompts = match &settings.prompt_deck {
+        Some(path) => PromptDeck::load(path)?,
+        None => PromptDeck::default(),
+    };
+    let filter = match &settings.content_filter {
+        Some(path) => ContentFilter::load(path, settings.content_filter_mode)?,
+        None => ContentFilter::default(),
+    };
+    let room_ttl = Duration::from_secs(settings.room_ttl_secs);
+    let (store, token_secret): (Arc<dyn RoomStore>, _) = match settings.store {
+        StoreKind::Redis => {
+            let store = RedisWrapper::new(settings.redis_url, room_ttl).await?;
+            // rooms outlive restarts, so the tokens for them have to as well
+            let token_secret = match settings.token_secret {
+                Some(secret) => secret,
+                None => store.token_secret().await?,
+            };
+            (Arc::new(store), Some(token_secret))
+        }
+        StoreKind::Memory => (Arc::new(MemoryStore::new(room_ttl)), settings.token_secret),
+    };
+
     let mut app = Router::new()
-        .route("/ws/player", any(player_upgrader))
-        .route("/ws/display", any(display_upgrader))
+        .route("/api/rooms/{code}", get(room_status))
+        .route("/ws/player/{code}", any(player_upgrader))
+        .route("/ws/display", any(new_display_upgrader))
+        .route("/ws/display/{code}", any(display_upgrader))
         .layer(TraceLayer::new_for_http())
-        .with_state(Arc::new(RedisWrapper::new(settings.redis_url).await?));
+        .with_state(AppState {
+            store,
+            tokens: TokenSigner::new(token_secret),
+            host_secret: settings.host_secret,
+            prompts: Arc::new(prompts),
+            filter: Arc::new(filter),
+            limiter: Arc::new(Limiter::new(settings.rate_limits)),
+            heartbeat: settings.heartbeat,
+            room_defaults: RoomDefaults {
+                name_limit: settings.name_limit,
+                duplicate_policy: settings.duplicate_policy,
+            },
+            max_message_size: settings.max_message_size,
+        });
     if let Some(serve_dir) = settings.serve_dir {
         app = app.fallback_service(
             ServeDir::new(&serve_dir).fallback(ServeFile::new(serve_dir.join("index.html"))),
@@ -85,9 +391,12 @@ async fn main() -> miette::Result<()> {
         .await
         .into_diagnostic()?;
     info!("Listening on {}", listener.local_addr().into_diagnostic()?);
-    axum::serve(listener, app.into_make_service())
-        .await
-        .into_diagnostic()?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .into_diagnostic()?;
 
     Ok(())
 }
@@ -0,0 +1,864 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use miette::bail;
+use rand::{rng, seq::SliceRandom};
+use uuid::Uuid;
+
+use crate::{
+    Epoch, GameState, Player, PlayerId, RoomCode,
+    names::dedup_key,
+    store::{
+        AddedName, CREATE_ROOM_ATTEMPTS, DuplicatePolicy, GameStore, GuessedName, RemovedName,
+        RoomChannels, RoomStore, RosterEntry, Score,
+    },
+};
+
+/// Keeps every room in process memory, for running without Valkey. All state
+/// is lost when the server restarts.
+#[derive(Debug)]
+pub struct MemoryStore {
+    rooms: Mutex<HashMap<RoomCode, Arc<MemoryRoom>>>,
+    /// How long rooms are kept after they were last touched.
+    room_ttl: Duration,
+}
+
+impl MemoryStore {
+    pub fn new(room_ttl: Duration) -> Self {
+        Self {
+            rooms: Mutex::default(),
+            room_ttl,
+        }
+    }
+}
+
+#[async_trait]
+impl RoomStore for MemoryStore {
+    async fn create_room(&self) -> miette::Result<Arc<dyn GameStore>> {
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms.retain(|_, room| !room.is_expired(self.room_ttl));
+        let Some(code) = (0..CREATE_ROOM_ATTEMPTS)
+            .map(|_| RoomCode::generate())
+            .find(|code| !rooms.contains_key(code))
+        else {
+            bail!("no free room code after {CREATE_ROOM_ATTEMPTS} attempts");
+        };
+        let room = Arc::new(MemoryRoom::new(code.clone()));
+        rooms.insert(code, room.clone());
+        Ok(room)
+    }
+
+    async fn room(&self, code: RoomCode) -> miette::Result<Option<Arc<dyn GameStore>>> {
+        let mut rooms = self.rooms.lock().unwrap();
+        if rooms
+            .get(&code)
+            .is_some_and(|room| room.is_expired(self.room_ttl))
+        {
+            rooms.remove(&code);
+        }
+        Ok(rooms
+            .get(&code)
+            .map(|room| room.clone() as Arc<dyn GameStore>))
+    }
+}
+
+#[derive(Debug, Default)]
+struct RoomData {
+    epoch: u32,
+    theme: Option<String>,
+    used_prompts: HashSet<String>,
+    name_limit: Option<u32>,
+    duplicate_policy: Option<DuplicatePolicy>,
+    players: HashMap<PlayerId, String>,
+    /// Live connections, the players they belong to and when they expire.
+    connections: HashMap<Uuid, (PlayerId, Instant)>,
+    /// Names and their authors in submission order, while submitting.
+    submitted: Vec<(Uuid, String, PlayerId)>,
+    /// Submitted names waiting for the host's approval, oldest first.
+    pending: Vec<Uuid>,
+    /// Shuffled names and their authors, while playing or revealing. Merged
+    /// names have several authors.
+    shuffled: Vec<(String, Vec<PlayerId>)>,
+    /// Bitmap with the same layout as a Valkey string after `SETBIT`.
+    guesses: Vec<u8>,
+    /// Who guessed each name, by index.
+    guessers: HashMap<usize, PlayerId>,
+    round_scores: HashMap<PlayerId, u32>,
+    total_scores: HashMap<PlayerId, u32>,
+}
+
+impl RoomData {
+    fn set_guess(&mut self, index: usize, guessed: bool) {
+        let byte = index / 8;
+        if self.guesses.len() <= byte {
+            self.guesses.resize(byte + 1, 0);
+        }
+        let mask = 0x80 >> (index % 8);
+        if guessed {
+            self.guesses[byte] |= mask;
+        } else {
+            self.guesses[byte] &= !mask;
+        }
+    }
+
+    fn is_guessed(&self, index: usize) -> bool {
+        self.guesses
+            .get(index / 8)
+            .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+    }
+
+    /// Takes the point for a name back from whoever guessed it, returning
+    /// whether anyone had.
+    fn take_point(&mut self, index: usize) -> bool {
+        let Some(previous) = self.guessers.remove(&index) else {
+            return false;
+        };
+        for scores in [&mut self.round_scores, &mut self.total_scores] {
+            if let Some(points) = scores.get_mut(&previous) {
+                *points = points.saturating_sub(1);
+            }
+        }
+        true
+    }
+
+    fn give_point(&mut self, index: usize, guesser: PlayerId) {
+        self.guessers.insert(index, guesser);
+        *self.round_scores.entry(guesser).or_default() += 1;
+        *self.total_scores.entry(guesser).or_default() += 1;
+    }
+}
+
+#[derive(Debug)]
+struct MemoryRoom {
+    code: RoomCode,
+    host_nonce: Uuid,
+    data: Mutex<RoomData>,
+    channels: RoomChannels,
+    touched: Mutex<Instant>,
+}
+
+impl MemoryRoom {
+    fn new(code: RoomCode) -> Self {
+        Self {
+            code,
+            host_nonce: Uuid::new_v4(),
+            data: Mutex::default(),
+            channels: RoomChannels::new(0, GameState::Submitting(Epoch(0))),
+            touched: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn is_expired(&self, ttl: Duration) -> bool {
+        self.touched.lock().unwrap().elapsed() >= ttl
+    }
+}
+
+#[async_trait]
+impl GameStore for MemoryRoom {
+    fn code(&self) -> &RoomCode {
+        &self.code
+    }
+
+    fn channels(&self) -> &RoomChannels {
+        &self.channels
+    }
+
+    async fn host_nonce(&self) -> miette::Result<Uuid> {
+        Ok(self.host_nonce)
+    }
+
+    async fn touch(&self) -> miette::Result<()> {
+        *self.touched.lock().unwrap() = Instant::now();
+        Ok(())
+    }
+
+    async fn set_nickname(&self, player: &Player) -> miette::Result<()> {
+        self.data
+            .lock()
+            .unwrap()
+            .players
+            .insert(player.id, player.nickname.clone());
+        self.channels.publish_scores_changed();
+        Ok(())
+    }
+
+    async fn nickname(&self, player: &PlayerId) -> miette::Result<Option<String>> {
+        Ok(self.data.lock().unwrap().players.get(player).cloned())
+    }
+
+    async fn add_connection(
+        &self,
+        id: &Uuid,
+        player: &PlayerId,
+        ttl: Duration,
+    ) -> miette::Result<()> {
+        let expires = Instant::now() + ttl;
+        let previous = self
+            .data
+            .lock()
+            .unwrap()
+            .connections
+            .insert(*id, (*player, expires));
+        if previous.is_none() {
+            self.channels.publish_presence_changed();
+        }
+        Ok(())
+    }
+
+    async fn remove_connection(&self, id: &Uuid) -> miette::Result<()> {
+        if self.data.lock().unwrap().connections.remove(id).is_some() {
+            self.channels.publish_presence_changed();
+        }
+        Ok(())
+    }
+
+    async fn roster(&self) -> miette::Result<Vec<RosterEntry>> {
+        let mut data = self.data.lock().unwrap();
+        let now = Instant::now();
+        let before = data.connections.len();
+        data.connections.retain(|_, (_, expires)| *expires > now);
+        if data.connections.len() < before {
+            self.channels.publish_presence_changed();
+        }
+
+        let players: HashSet<_> = data
+            .connections
+            .values()
+            .map(|(player, _)| *player)
+            .collect();
+        let mut roster: Vec<_> = players
+            .into_iter()
+            .map(|player| RosterEntry {
+                player,
+                nickname: data.players.get(&player).cloned().unwrap_or_default(),
+                names: data
+                    .submitted
+                    .iter()
+                    .filter(|(_, _, author)| *author == player)
+                    .count() as u32,
+            })
+            .collect();
+        roster.sort_by(|a, b| {
+            a.nickname
+                .cmp(&b.nickname)
+                .then(a.player.0.cmp(&b.player.0))
+        });
+        Ok(roster)
+    }
+
+    async fn add_name(
+        &self,
+        name: &str,
+        author: &PlayerId,
+        limit: Option<u32>,
+        reject_duplicates: bool,
+        hold: bool,
+    ) -> miette::Result<AddedName> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(limit) = limit {
+            let count = data
+                .submitted
+                .iter()
+                .filter(|(_, _, other)| other == author)
+                .count();
+            if count >= limit as usize {
+                return Ok(AddedName::LimitReached);
+            }
+        }
+        if reject_duplicates {
+            let key = dedup_key(name);
+            if data
+                .submitted
+                .iter()
+                .any(|(_, other, _)| dedup_key(other) == key)
+            {
+                return Ok(AddedName::Duplicate);
+            }
+        }
+        let id = Uuid::new_v4();
+        data.submitted.push((id, name.to_string(), *author));
+        self.channels.set_name_count(data.submitted.len());
+        if hold {
+            data.pending.push(id);
+            self.channels.publish_pending_changed();
+        } else {
+            self.channels.publish_submitted(name.to_string(), id);
+        }
+        Ok(AddedName::Added(id))
+    }
+
+    async fn remove_name(&self, id: &Uuid, author: &PlayerId) -> miette::Result<RemovedName> {
+        let mut data = self.data.lock().unwrap();
+        let Some(index) = data.submitted.iter().position(|(other, _, _)| other == id) else {
+            return Ok(RemovedName::NoSuchName);
+        };
+        // only the author may remove a name
+        if data.submitted[index].2 != *author {
+            return Ok(RemovedName::NotAuthor);
+        }
+        data.submitted.remove(index);
+        self.channels.set_name_count(data.submitted.len());
+        self.channels.publish_unsubmitted(*id);
+        if let Some(index) = data.pending.iter().position(|other| other == id) {
+            data.pending.remove(index);
+            self.channels.publish_pending_changed();
+        }
+        Ok(RemovedName::Removed)
+    }
+
+    async fn remove_name_as_host(&self, id: &Uuid) -> miette::Result<bool> {
+        let mut data = self.data.lock().unwrap();
+        let Some(index) = data.submitted.iter().position(|(other, _, _)| other == id) else {
+            return Ok(false);
+        };
+        let (_, _, author) = data.submitted.remove(index);
+        self.channels.set_name_count(data.submitted.len());
+        self.channels.publish_unsubmitted(*id);
+        if let Some(index) = data.pending.iter().position(|other| other == id) {
+            data.pending.remove(index);
+            self.channels.publish_pending_changed();
+        }
+        self.channels.publish_rejection(*id, author);
+        Ok(true)
+    }
+
+    async fn remove_name_at(&self, index: usize) -> miette::Result<bool> {
+        let mut data = self.data.lock().unwrap();
+        if index >= data.shuffled.len() {
+            return Ok(false);
+        }
+        data.shuffled.remove(index);
+        if data.take_point(index) {
+            self.channels.publish_scores_changed();
+        }
+        // shift the later names' guesses and guessers down by one
+        let count = data.shuffled.len();
+        for i in index..count {
+            let guessed = data.is_guessed(i + 1);
+            data.set_guess(i, guessed);
+            if let Some(guesser) = data.guessers.remove(&(i + 1)) {
+                data.guessers.insert(i, guesser);
+            }
+        }
+        data.set_guess(count, false);
+        self.channels.set_name_count(count);
+        self.channels.publish_names_changed();
+        Ok(true)
+    }
+
+    async fn submitted_names(&self) -> miette::Result<Vec<(String, Uuid)>> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .submitted
+            .iter()
+            .filter(|(id, _, _)| !data.pending.contains(id))
+            .map(|(id, name, _)| (name.clone(), *id))
+            .collect())
+    }
+
+    async fn pending_names(&self) -> miette::Result<Vec<(String, Uuid)>> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .pending
+            .iter()
+            .filter_map(|id| {
+                let (_, name, _) = data.submitted.iter().find(|(other, _, _)| other == id)?;
+                Some((name.clone(), *id))
+            })
+            .collect())
+    }
+
+    async fn approve_name(&self, id: &Uuid) -> miette::Result<bool> {
+        let mut data = self.data.lock().unwrap();
+        let Some(index) = data.pending.iter().position(|other| other == id) else {
+            return Ok(false);
+        };
+        data.pending.remove(index);
+        self.channels.publish_pending_changed();
+        if let Some((_, name, _)) = data.submitted.iter().find(|(other, _, _)| other == id) {
+            self.channels.publish_submitted(name.clone(), *id);
+        }
+        Ok(true)
+    }
+
+    async fn reject_name(&self, id: &Uuid) -> miette::Result<bool> {
+        let mut data = self.data.lock().unwrap();
+        let Some(index) = data.pending.iter().position(|other| other == id) else {
+            return Ok(false);
+        };
+        data.pending.remove(index);
+        if let Some(index) = data.submitted.iter().position(|(other, _, _)| other == id) {
+            let (_, _, author) = data.submitted.remove(index);
+            self.channels.set_name_count(data.submitted.len());
+            self.channels.publish_rejection(*id, author);
+        }
+        self.channels.publish_pending_changed();
+        Ok(true)
+    }
+
+    async fn player_names(&self, player: &PlayerId) -> miette::Result<Vec<(String, Uuid)>> {
+        Ok(self
+            .data
+            .lock()
+            .unwrap()
+            .submitted
+            .iter()
+            .filter(|(_, _, author)| author == player)
+            .map(|(id, name, _)| (name.clone(), *id))
+            .collect())
+    }
+
+    async fn names_and_guesses(&self) -> miette::Result<(Vec<String>, Vec<u8>)> {
+        let data = self.data.lock().unwrap();
+        let names: Vec<String> = match self.state() {
+            GameState::Submitting(_) => data
+                .submitted
+                .iter()
+                .map(|(_, name, _)| name.clone())
+                .collect(),
+            GameState::Playing | GameState::Revealing => {
+                data.shuffled.iter().map(|(name, _)| name.clone()).collect()
+            }
+        };
+        let mut guesses = data.guesses.clone();
+        guesses.truncate(names.len().div_ceil(8));
+        Ok((names, guesses))
+    }
+
+    async fn authors(&self) -> miette::Result<Vec<String>> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .shuffled
+            .iter()
+            .map(|(_, authors)| {
+                authors
+                    .iter()
+                    .map(|author| data.players.get(author).cloned().unwrap_or_default())
+                    .collect::<Vec<_>>()
+                    .join(" & ")
+            })
+            .collect())
+    }
+
+    async fn guess_name(
+        &self,
+        index: usize,
+        guesser: Option<&PlayerId>,
+        reassign: bool,
+    ) -> miette::Result<GuessedName> {
+        let mut data = self.data.lock().unwrap();
+        // nobody gets to guess their own name
+        if let Some(guesser) = guesser
+            && data
+                .shuffled
+                .get(index)
+                .is_some_and(|(_, authors)| authors.contains(guesser))
+        {
+            return Ok(GuessedName::OwnName);
+        }
+        if !reassign && data.is_guessed(index) {
+            return Ok(GuessedName::AlreadyGuessed);
+        }
+
+        data.set_guess(index, true);
+        self.channels.publish_guess(index);
+
+        if data.guessers.get(&index) != guesser {
+            data.take_point(index);
+            if let Some(guesser) = guesser {
+                data.give_point(index, *guesser);
+            }
+            self.channels.publish_scores_changed();
+        }
+        Ok(GuessedName::Guessed)
+    }
+
+    async fn unguess_name(&self, index: usize) -> miette::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        data.set_guess(index, false);
+        self.channels.publish_unguess(index);
+        if data.take_point(index) {
+            self.channels.publish_scores_changed();
+        }
+        Ok(())
+    }
+
+    async fn scoreboard(&self) -> miette::Result<Vec<Score>> {
+        let data = self.data.lock().unwrap();
+        let mut scores: Vec<_> = data
+            .players
+            .iter()
+            .map(|(player, nickname)| Score {
+                player: *player,
+                nickname: nickname.clone(),
+                round_points: data.round_scores.get(player).copied().unwrap_or(0),
+                total_points: data.total_scores.get(player).copied().unwrap_or(0),
+            })
+            .collect();
+        Score::sort(&mut scores);
+        Ok(scores)
+    }
+
+    async fn theme(&self) -> miette::Result<Option<String>> {
+        Ok(self.data.lock().unwrap().theme.clone())
+    }
+
+    async fn name_limit(&self) -> miette::Result<Option<u32>> {
+        Ok(self.data.lock().unwrap().name_limit)
+    }
+
+    async fn set_name_limit(&self, limit: u32) -> miette::Result<()> {
+        self.data.lock().unwrap().name_limit = Some(limit);
+        self.channels.publish_name_limit_changed();
+        Ok(())
+    }
+
+    async fn duplicate_policy(&self) -> miette::Result<Option<DuplicatePolicy>> {
+        Ok(self.data.lock().unwrap().duplicate_policy)
+    }
+
+    async fn set_duplicate_policy(&self, policy: DuplicatePolicy) -> miette::Result<()> {
+        self.data.lock().unwrap().duplicate_policy = Some(policy);
+        Ok(())
+    }
+
+    async fn claim_prompt(&self, prompt: &str) -> miette::Result<bool> {
+        Ok(self
+            .data
+            .lock()
+            .unwrap()
+            .used_prompts
+            .insert(prompt.to_string()))
+    }
+
+    async fn change_state_to_submitting(&self, theme: Option<&str>) -> miette::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        data.submitted.clear();
+        data.pending.clear();
+        data.shuffled.clear();
+        data.guesses.clear();
+        data.guessers.clear();
+        data.round_scores.clear();
+        data.epoch += 1;
+        data.theme = theme.map(str::to_string);
+        self.channels.enter_submitting(Epoch(data.epoch));
+        self.channels.publish_scores_changed();
+        Ok(())
+    }
+
+    async fn change_state_to_playing(&self, merge_duplicates: bool) -> miette::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        let mut names: Vec<(String, Vec<PlayerId>)> = Vec::new();
+        let mut keys: HashMap<String, usize> = HashMap::new();
+        let pending = std::mem::take(&mut data.pending);
+        for (id, name, author) in data.submitted.drain(..) {
+            if pending.contains(&id) {
+                continue;
+            }
+            if merge_duplicates {
+                if let Some(&index) = keys.get(&dedup_key(&name)) {
+                    names[index].1.push(author);
+                    continue;
+                }
+                keys.insert(dedup_key(&name), names.len());
+            }
+            names.push((name, vec![author]));
+        }
+        names.shuffle(&mut rng());
+        // merging or dropping held names may have left fewer names than were
+        // submitted
+        self.channels.set_name_count(names.len());
+        data.shuffled = names;
+        self.channels.enter_playing();
+        Ok(())
+    }
+
+    async fn change_state_to_revealing(&self) -> miette::Result<()> {
+        self.channels.enter_revealing();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use secrecy::SecretString;
+
+    use super::*;
+    use crate::token::TokenSigner;
+
+    fn room() -> MemoryRoom {
+        MemoryRoom::new(RoomCode::try_from("BCDF".to_string()).unwrap())
+    }
+
+    async fn join(room: &MemoryRoom, nickname: &str) -> PlayerId {
+        let player = Player::new(PlayerId::new(), Some(nickname.to_string()));
+        room.set_nickname(&player).await.unwrap();
+        player.id
+    }
+
+    async fn add(room: &MemoryRoom, name: &str, author: &PlayerId) -> Uuid {
+        match room
+            .add_name(name, author, None, false, false)
+            .await
+            .unwrap()
+        {
+            AddedName::Added(id) => id,
+            added => panic!("couldn't add {name}: {added:?}"),
+        }
+    }
+
+    /// Index of a name after shuffling.
+    async fn index_of(room: &MemoryRoom, name: &str) -> usize {
+        let (names, _) = room.names_and_guesses().await.unwrap();
+        names.iter().position(|other| other == name).unwrap()
+    }
+
+    fn points(scores: &[Score], player: &PlayerId) -> (u32, u32) {
+        let score = scores.iter().find(|score| score.player == *player).unwrap();
+        (score.round_points, score.total_points)
+    }
+
+    #[tokio::test]
+    async fn names_keep_their_authors() {
+        let room = room();
+        let ann = join(&room, "Ann").await;
+        let bob = join(&room, "Bob").await;
+        let ada = add(&room, "Ada", &ann).await;
+        let bea = add(&room, "Bea", &bob).await;
+        assert_eq!(room.name_count(), 2);
+        assert_eq!(
+            room.player_names(&ann).await.unwrap(),
+            [("Ada".to_string(), ada)]
+        );
+
+        // only the author may take a name back
+        assert_eq!(
+            room.remove_name(&ada, &bob).await.unwrap(),
+            RemovedName::NotAuthor
+        );
+        assert_eq!(room.name_count(), 2);
+        assert_eq!(
+            room.remove_name(&ada, &ann).await.unwrap(),
+            RemovedName::Removed
+        );
+        assert_eq!(
+            room.remove_name(&ada, &ann).await.unwrap(),
+            RemovedName::NoSuchName
+        );
+        assert_eq!(room.name_count(), 1);
+        assert!(room.player_names(&ann).await.unwrap().is_empty());
+        assert_eq!(
+            room.submitted_names().await.unwrap(),
+            [("Bea".to_string(), bea)]
+        );
+    }
+
+    #[tokio::test]
+    async fn limit_applies_per_author() {
+        let room = room();
+        let ann = join(&room, "Ann").await;
+        let bob = join(&room, "Bob").await;
+        add(&room, "Ada", &ann).await;
+        assert_eq!(
+            room.add_name("Alan", &ann, Some(1), false, false)
+                .await
+                .unwrap(),
+            AddedName::LimitReached
+        );
+        assert!(matches!(
+            room.add_name("Alan", &bob, Some(1), false, false)
+                .await
+                .unwrap(),
+            AddedName::Added(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn duplicates_ignore_case_and_accents() {
+        let room = room();
+        let ann = join(&room, "Ann").await;
+        let bob = join(&room, "Bob").await;
+        add(&room, "Zoë", &ann).await;
+        assert_eq!(
+            room.add_name("ZOE", &bob, None, true, false).await.unwrap(),
+            AddedName::Duplicate
+        );
+        // unless duplicates are allowed
+        assert!(matches!(
+            room.add_name("ZOE", &bob, None, false, false)
+                .await
+                .unwrap(),
+            AddedName::Added(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn held_names_wait_for_approval() {
+        let room = room();
+        let ann = join(&room, "Ann").await;
+        let AddedName::Added(held) = room.add_name("Ada", &ann, None, false, true).await.unwrap()
+        else {
+            panic!("couldn't hold name");
+        };
+        let dropped = match room.add_name("Bea", &ann, None, false, true).await.unwrap() {
+            AddedName::Added(id) => id,
+            added => panic!("couldn't hold name: {added:?}"),
+        };
+        assert!(room.submitted_names().await.unwrap().is_empty());
+        assert_eq!(
+            room.pending_names().await.unwrap(),
+            [("Ada".to_string(), held), ("Bea".to_string(), dropped)]
+        );
+
+        assert!(room.approve_name(&held).await.unwrap());
+        assert!(!room.approve_name(&held).await.unwrap());
+        assert_eq!(
+            room.submitted_names().await.unwrap(),
+            [("Ada".to_string(), held)]
+        );
+
+        // names still held when the round starts are left out
+        room.change_state_to_playing(false).await.unwrap();
+        assert_eq!(room.names_and_guesses().await.unwrap().0, ["Ada"]);
+    }
+
+    #[tokio::test]
+    async fn rejected_names_are_removed() {
+        let room = room();
+        let ann = join(&room, "Ann").await;
+        let mut rejections = room.rejection_stream();
+        let AddedName::Added(id) = room.add_name("Ada", &ann, None, false, true).await.unwrap()
+        else {
+            panic!("couldn't hold name");
+        };
+        assert!(room.reject_name(&id).await.unwrap());
+        assert_eq!(room.name_count(), 0);
+        assert!(room.pending_names().await.unwrap().is_empty());
+        assert_eq!(rejections.next().await, Some((id, ann)));
+    }
+
+    #[tokio::test]
+    async fn removing_a_name_shifts_later_guesses_and_points() {
+        let room = room();
+        let ann = join(&room, "Ann").await;
+        let bob = join(&room, "Bob").await;
+        for name in ["Ada", "Bea", "Cy"] {
+            add(&room, name, &ann).await;
+        }
+        room.change_state_to_playing(false).await.unwrap();
+        let (names, _) = room.names_and_guesses().await.unwrap();
+
+        // bob guesses the second and third names, then the second is removed
+        for index in [1, 2] {
+            assert_eq!(
+                room.guess_name(index, Some(&bob), false).await.unwrap(),
+                GuessedName::Guessed
+            );
+        }
+        assert_eq!(points(&room.scoreboard().await.unwrap(), &bob), (2, 2));
+        assert!(room.remove_name_at(1).await.unwrap());
+        assert!(!room.remove_name_at(2).await.unwrap());
+
+        let (remaining, guesses) = room.names_and_guesses().await.unwrap();
+        assert_eq!(remaining, [names[0].clone(), names[2].clone()]);
+        assert_eq!(guesses, [0b0100_0000]);
+        assert_eq!(room.name_count(), 2);
+        assert_eq!(points(&room.scoreboard().await.unwrap(), &bob), (1, 1));
+
+        // the point for the name that moved up went with it
+        room.unguess_name(1).await.unwrap();
+        assert_eq!(points(&room.scoreboard().await.unwrap(), &bob), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn guesses_credit_the_guesser_once() {
+        let room = room();
+        let ann = join(&room, "Ann").await;
+        let bob = join(&room, "Bob").await;
+        let cy = join(&room, "Cy").await;
+        add(&room, "Ada", &ann).await;
+        room.change_state_to_playing(false).await.unwrap();
+
+        assert_eq!(
+            room.guess_name(0, Some(&ann), false).await.unwrap(),
+            GuessedName::OwnName
+        );
+        assert_eq!(
+            room.guess_name(0, Some(&bob), false).await.unwrap(),
+            GuessedName::Guessed
+        );
+        assert_eq!(
+            room.guess_name(0, Some(&cy), false).await.unwrap(),
+            GuessedName::AlreadyGuessed
+        );
+        let scores = room.scoreboard().await.unwrap();
+        assert_eq!(points(&scores, &bob), (1, 1));
+        assert_eq!(points(&scores, &cy), (0, 0));
+
+        // the host can still say who really guessed it
+        assert_eq!(
+            room.guess_name(0, Some(&cy), true).await.unwrap(),
+            GuessedName::Guessed
+        );
+        let scores = room.scoreboard().await.unwrap();
+        assert_eq!(points(&scores, &bob), (0, 0));
+        assert_eq!(points(&scores, &cy), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn rounds_go_through_every_state() {
+        let room = room();
+        let ann = join(&room, "Ann").await;
+        let bob = join(&room, "Bob").await;
+        assert_eq!(room.state(), GameState::Submitting(Epoch(0)));
+        add(&room, "Ada", &ann).await;
+        add(&room, "ADA", &bob).await;
+        add(&room, "Bea", &bob).await;
+
+        // duplicates are merged into one name with both authors
+        room.change_state_to_playing(true).await.unwrap();
+        assert_eq!(room.state(), GameState::Playing);
+        assert_eq!(room.name_count(), 2);
+        let ada = index_of(&room, "Ada").await;
+        room.guess_name(1 - ada, Some(&ann), false).await.unwrap();
+
+        room.change_state_to_revealing().await.unwrap();
+        assert_eq!(room.state(), GameState::Revealing);
+        let authors = room.authors().await.unwrap();
+        assert_eq!(authors[ada], "Ann & Bob");
+        assert_eq!(authors[1 - ada], "Bob");
+
+        // a new round starts from scratch, except for the total scores
+        room.change_state_to_submitting(Some("Pets")).await.unwrap();
+        assert_eq!(room.state(), GameState::Submitting(Epoch(1)));
+        assert_eq!(room.theme().await.unwrap().as_deref(), Some("Pets"));
+        assert_eq!(room.name_count(), 0);
+        assert!(room.submitted_names().await.unwrap().is_empty());
+        assert_eq!(points(&room.scoreboard().await.unwrap(), &ann), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn host_tokens_die_with_their_room() {
+        let tokens = TokenSigner::new(Some(SecretString::from("secret")));
+        let store = MemoryStore::new(Duration::ZERO);
+        let old = store.create_room().await.unwrap();
+        let token = tokens.issue_host(old.code(), &old.host_nonce().await.unwrap());
+
+        // the room expires, and its code comes up again for a new one
+        let new = Arc::new(MemoryRoom::new(old.code().clone()));
+        assert!(store.room(old.code().clone()).await.unwrap().is_none());
+        store
+            .rooms
+            .lock()
+            .unwrap()
+            .insert(new.code.clone(), new.clone());
+        let nonce = new.host_nonce().await.unwrap();
+        assert!(!tokens.verify_host(new.code(), &nonce, &token));
+    }
+}
@@ -1,161 +1,488 @@
 use bytes::{Buf, Bytes};
 use miette::{Context, IntoDiagnostic, bail};
-use serde_bytes::ByteBuf;
+use serde::{
+    Deserialize,
+    de::{IntoDeserializer, value::StrDeserializer},
+};
 use uuid::Uuid;
 
-use crate::Epoch;
-
-#[derive(Clone, Debug)]
-pub enum NGMessage {
-    StateSubmitting(Epoch),
-    SubmitName(String),
-    NameSubmitted(String, Uuid),
-    UnsubmitName(Uuid),
-    NameUnsubmitted(Uuid),
-    NumNames(usize),
-    RequestPlayingState,
-    Names(Vec<String>, Vec<u8>),
-    GuessName(usize),
-    NameGuessed(usize),
-    UnguessName(usize),
-    NameUnguessed(usize),
-    RequestSubmittingState,
+use crate::{
+    Epoch, PlayerId, RoomCode,
+    names::duplicate_flags,
+    schema::{Declarations, TsType, camel_case},
+    store::{DuplicatePolicy, RosterEntry, Score},
+};
+
+/// Why a request was rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorCode {
+    /// The request isn't allowed in the room's current state.
+    WrongState,
+    /// The request refers to a name that doesn't exist.
+    InvalidIndex,
+    EmptyName,
+    NameTooLong,
+    InvalidCharacters,
+    ThemeTooLong,
+    /// There's no prompt deck, or every prompt in it has been used.
+    NoPromptsLeft,
+    /// The player already submitted as many names as they may this round.
+    NameLimitReached,
+    /// Somebody already submitted the same name, and the room rejects
+    /// duplicates.
+    DuplicateName,
+    /// The name contains a word from the content filter.
+    InappropriateName,
+    /// Some names are still waiting for the host's approval.
+    NamesPending,
+    /// The name isn't waiting for approval, or doesn't exist.
+    NoSuchName,
+    /// The client sent messages too fast, and this one was dropped.
+    RateLimited,
+    /// The client isn't allowed to make the request at all.
+    Unauthorized,
+    /// The client sent a message only the server should send.
+    UnexpectedMessage,
+    /// The client speaks an older protocol than the server still supports,
+    /// usually because it's a tab left open from before a deploy.
+    UnsupportedVersion,
+    /// Somebody already guessed the name.
+    AlreadyGuessed,
 }
 
-impl NGMessage {
-    pub fn parse(mut bytes: Bytes) -> miette::Result<Self> {
-        let typ = bytes.get_u32();
-        match typ {
-            0 => Ok(NGMessage::StateSubmitting(
-                rmp_serde::from_slice(&bytes)
-                    .into_diagnostic()
-                    .wrap_err("parse content from StateSubmitting message")?,
-            )),
-            1 => Ok(NGMessage::SubmitName(
-                rmp_serde::from_slice(&bytes)
-                    .into_diagnostic()
-                    .wrap_err("parse content from SubmitName message")?,
-            )),
-            2 => {
-                let (name, id) = rmp_serde::from_slice(&bytes)
+/// Version of the protocol this server speaks. Bump it whenever a change
+/// would confuse older clients, and have new messages check the version a
+/// connection negotiated before sending them.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest protocol version this server still talks to.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// What kind of page is on the other end of a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientKind {
+    Player,
+    Display,
+}
+
+/// Optional feature a client can handle. The server only sends the messages
+/// belonging to a feature to clients that asked for it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Capability {
+    /// The player shows how many names they may still submit.
+    NameQuota,
+    /// The host display can approve or reject held names. Without it, held
+    /// names are rejected when the round starts.
+    Moderation,
+    /// The display shows who's connected and how many names each submitted.
+    Presence,
+}
+
+impl Capability {
+    /// Looks up a capability by the name clients use for it.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name: StrDeserializer<'_, serde::de::value::Error> = name.into_deserializer();
+        Self::deserialize(name).ok()
+    }
+}
+
+/// A name the host refers to: by id while submitting, and by index once the
+/// names are shuffled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum NameRef {
+    Id(Uuid),
+    Index(usize),
+}
+
+/// One bit per name, packed into bytes with the first name in the highest bit.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct Bitmap(#[serde(with = "serde_bytes")] pub Vec<u8>);
+
+/// MessagePack nil, which an empty payload stands for.
+const NIL: &[u8] = &[0xc0];
+
+/// A message in text frames, e.g. `{"type": "SubmitName", "content": "Ann"}`.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct JsonMessage {
+    #[serde(rename = "type")]
+    typ: String,
+    #[serde(default, skip_serializing_if = "serde_json::Value::is_null")]
+    content: serde_json::Value,
+}
+
+/// Describes every message once, generating the enum, its wire format and
+/// the TypeScript definitions the frontend is checked against.
+///
+/// Each message is sent as its type id (a big-endian `u32`) followed by its
+/// fields in MessagePack: a lone field as itself, several as an array. A
+/// payload that would be nil is left out entirely. Text frames carry the same
+/// fields as JSON instead, next to the name of the message.
+macro_rules! messages {
+    (
+        $(
+            $(#[doc = $doc:literal])*
+            $id:literal => $variant:ident
+            $(( $($tuple_field:ident: $tuple_ty:ty),+ $(,)? ))?
+            $({ $($(#[doc = $field_doc:literal])* $struct_field:ident: $struct_ty:ty),+ $(,)? })?
+        ),+ $(,)?
+    ) => {
+        #[derive(Clone, Debug)]
+        pub enum NGMessage {
+            $(
+                $(#[doc = $doc])*
+                $variant
+                $(( $($tuple_ty),+ ))?
+                $({ $($(#[doc = $field_doc])* $struct_field: $struct_ty),+ })?,
+            )+
+        }
+
+        impl NGMessage {
+            pub fn parse(mut bytes: Bytes) -> miette::Result<Self> {
+                let typ = bytes
+                    .try_get_u32()
                     .into_diagnostic()
-                    .wrap_err("parse content from NameSubmitted message")?;
-                Ok(NGMessage::NameSubmitted(name, id))
+                    .wrap_err("message is too short to have a type")?;
+                let payload = if bytes.is_empty() { NIL } else { &bytes[..] };
+                match typ {
+                    $(
+                        $id => {
+                            let messages!(@fields $($($tuple_field),+)? $($($struct_field),+)?):
+                                messages!(@types $($($tuple_ty),+)? $($($struct_ty),+)?) =
+                                rmp_serde::from_slice(payload)
+                                    .into_diagnostic()
+                                    .wrap_err(concat!(
+                                        "parse content from ",
+                                        stringify!($variant),
+                                        " message"
+                                    ))?;
+                            Ok(NGMessage::$variant
+                                $(( $($tuple_field),+ ))?
+                                $({ $($struct_field),+ })?)
+                        }
+                    )+
+                    _ => {
+                        bail!("message has unknown type: {typ}");
+                    }
+                }
             }
-            3 => Ok(NGMessage::UnsubmitName(
-                rmp_serde::from_slice(&bytes)
-                    .into_diagnostic()
-                    .wrap_err("parse content from UnsubmitName message")?,
-            )),
-            4 => Ok(NGMessage::NameUnsubmitted(
-                rmp_serde::from_slice(&bytes)
-                    .into_diagnostic()
-                    .wrap_err("parse content from NameUnsubmitted message")?,
-            )),
-            5 => Ok(NGMessage::NumNames(
-                rmp_serde::from_slice(&bytes)
+
+            pub fn parse_json(text: &str) -> miette::Result<Self> {
+                let JsonMessage { typ, content } = serde_json::from_str(text)
                     .into_diagnostic()
-                    .wrap_err("parse content from NumNames message")?,
-            )),
-            6 => {
-                if !bytes.is_empty() {
-                    bail!(
-                        "nonzero length in RequestPlayingState message: {}",
-                        bytes.len()
-                    );
-                } else {
-                    Ok(NGMessage::RequestPlayingState)
+                    .wrap_err("message isn't an object with a type")?;
+                match typ.as_str() {
+                    $(
+                        stringify!($variant) => {
+                            let messages!(@fields $($($tuple_field),+)? $($($struct_field),+)?):
+                                messages!(@types $($($tuple_ty),+)? $($($struct_ty),+)?) =
+                                serde_json::from_value(content)
+                                    .into_diagnostic()
+                                    .wrap_err(concat!(
+                                        "parse content from ",
+                                        stringify!($variant),
+                                        " message"
+                                    ))?;
+                            Ok(NGMessage::$variant
+                                $(( $($tuple_field),+ ))?
+                                $({ $($struct_field),+ })?)
+                        }
+                    )+
+                    _ => {
+                        bail!("message has unknown type: {typ}");
+                    }
                 }
             }
-            7 => {
-                let (names, guesses): (Vec<String>, ByteBuf) = rmp_serde::from_slice(&bytes)
-                    .into_diagnostic()
-                    .wrap_err("parse content from Names message")?;
-                Ok(NGMessage::Names(names, guesses.into_vec()))
+
+            pub fn type_id(&self) -> u32 {
+                match self {
+                    $(NGMessage::$variant { .. } => $id,)+
+                }
             }
-            8 => Ok(NGMessage::GuessName(
-                rmp_serde::from_slice(&bytes)
-                    .into_diagnostic()
-                    .wrap_err("parse content from GuessName message")?,
-            )),
-            9 => Ok(NGMessage::NameGuessed(
-                rmp_serde::from_slice(&bytes)
-                    .into_diagnostic()
-                    .wrap_err("parse content from NameGuessed message")?,
-            )),
-            10 => Ok(NGMessage::UnguessName(
-                rmp_serde::from_slice(&bytes)
-                    .into_diagnostic()
-                    .wrap_err("parse content from UnguessName message")?,
-            )),
-            11 => Ok(NGMessage::NameUnguessed(
-                rmp_serde::from_slice(&bytes)
-                    .into_diagnostic()
-                    .wrap_err("parse content from NameUnguessed message")?,
-            )),
-            12 => {
-                if !bytes.is_empty() {
-                    bail!(
-                        "nonzero length in RequestSubmittingState message: {}",
-                        bytes.len()
-                    );
-                } else {
-                    Ok(NGMessage::RequestSubmittingState)
+
+            pub fn encode(&self) -> Bytes {
+                let mut encoded = self.type_id().to_be_bytes().to_vec();
+                match self {
+                    $(
+                        NGMessage::$variant
+                            $(( $($tuple_field),+ ))?
+                            $({ $($struct_field),+ })? => rmp_serde::encode::write(
+                            &mut encoded,
+                            &messages!(@fields $($($tuple_field),+)? $($($struct_field),+)?),
+                        )
+                        .unwrap(),
+                    )+
+                }
+                if encoded[4..] == *NIL {
+                    encoded.truncate(4);
                 }
+                Bytes::from(encoded)
             }
-            _ => {
-                bail!("message has unknown type: {typ}");
+
+            pub fn encode_json(&self) -> String {
+                let (typ, content) = match self {
+                    $(
+                        NGMessage::$variant
+                            $(( $($tuple_field),+ ))?
+                            $({ $($struct_field),+ })? => (
+                            stringify!($variant),
+                            serde_json::to_value(
+                                messages!(@fields $($($tuple_field),+)? $($($struct_field),+)?),
+                            )
+                            .unwrap(),
+                        ),
+                    )+
+                };
+                serde_json::to_string(&JsonMessage {
+                    typ: typ.to_string(),
+                    content,
+                })
+                .unwrap()
             }
         }
-    }
 
-    pub fn encode(&self) -> Bytes {
-        let mut encoded = vec![0; 4];
-
-        encoded[..4].copy_from_slice(
-            &match self {
-                NGMessage::StateSubmitting(_) => 0u32,
-                NGMessage::SubmitName(_) => 1,
-                NGMessage::NameSubmitted(_, _) => 2,
-                NGMessage::UnsubmitName(_) => 3,
-                NGMessage::NameUnsubmitted(_) => 4,
-                NGMessage::NumNames(_) => 5,
-                NGMessage::RequestPlayingState => 6,
-                NGMessage::Names(_, _) => 7,
-                NGMessage::GuessName(_) => 8,
-                NGMessage::NameGuessed(_) => 9,
-                NGMessage::UnguessName(_) => 10,
-                NGMessage::NameUnguessed(_) => 11,
-                NGMessage::RequestSubmittingState => 12,
-            }
-            .to_be_bytes(),
-        );
+        /// TypeScript definitions of every message, for `--emit-ts-schema`.
+        pub fn ts_schema() -> String {
+            let mut decls = Declarations::new();
+            $($(
+                $(<$tuple_ty as TsType>::declare(&mut decls);)+
+            )?$(
+                $(<$struct_ty as TsType>::declare(&mut decls);)+
+            )?)+
 
-        match self {
-            NGMessage::StateSubmitting(epoch) => {
-                rmp_serde::encode::write(&mut encoded, epoch).unwrap()
-            }
-            NGMessage::SubmitName(name) => rmp_serde::encode::write(&mut encoded, name).unwrap(),
-            NGMessage::NameSubmitted(name, id) => {
-                rmp_serde::encode::write(&mut encoded, &(name, id)).unwrap()
+            let mut ts = String::from(
+                "// Generated by `backend --emit-ts-schema`, don't edit by hand.\n\n",
+            );
+            ts += &format!("export const PROTOCOL_VERSION = {PROTOCOL_VERSION};\n\n");
+            ts += "export enum MessageType {\n";
+            $(ts += &format!("  {} = {},\n", stringify!($variant), $id);)+
+            ts += "}\n";
+            for decl in decls.values() {
+                ts += &format!("\n{decl}\n");
             }
-            NGMessage::UnsubmitName(id) => rmp_serde::encode::write(&mut encoded, id).unwrap(),
-            NGMessage::NameUnsubmitted(id) => rmp_serde::encode::write(&mut encoded, id).unwrap(),
-            NGMessage::NumNames(num) => rmp_serde::encode::write(&mut encoded, num).unwrap(),
-            NGMessage::RequestPlayingState => {}
-            NGMessage::Names(names, guesses) => {
-                rmp_serde::encode::write(&mut encoded, &(names, serde_bytes::Bytes::new(guesses)))
-                    .unwrap()
-            }
-            NGMessage::GuessName(index) => rmp_serde::encode::write(&mut encoded, index).unwrap(),
-            NGMessage::NameGuessed(index) => rmp_serde::encode::write(&mut encoded, index).unwrap(),
-            NGMessage::UnguessName(index) => rmp_serde::encode::write(&mut encoded, index).unwrap(),
-            NGMessage::NameUnguessed(index) => {
-                rmp_serde::encode::write(&mut encoded, index).unwrap()
+            $(
+                ts += "\n";
+                let docs: &[&str] = &[$($doc),*];
+                if !docs.is_empty() {
+                    ts += "/**\n";
+                    for line in docs {
+                        ts += &format!(" *{line}\n");
+                    }
+                    ts += " */\n";
+                }
+                let fields: &[(&str, String)] = &[
+                    $($((stringify!($tuple_field), <$tuple_ty as TsType>::ts_type())),+)?
+                    $($((stringify!($struct_field), <$struct_ty as TsType>::ts_type())),+)?
+                ];
+                ts += &format!(
+                    "export type {}Message = {{\n  type: MessageType.{};\n  content: {};\n}};\n",
+                    stringify!($variant),
+                    stringify!($variant),
+                    ts_content(fields),
+                );
+            )+
+            ts += "\nexport type Message =";
+            $(ts += concat!("\n  | ", stringify!($variant), "Message");)+
+            ts += ";\n";
+            ts
+        }
+    };
+    (@fields) => { () };
+    (@fields $field:ident) => { $field };
+    (@fields $($field:ident),+) => { ($($field),+) };
+    (@types) => { () };
+    (@types $ty:ty) => { $ty };
+    (@types $($ty:ty),+) => { ($($ty),+) };
+}
+
+/// Content of a message in TypeScript: nothing, a lone field, or a labelled
+/// tuple of several, one per line if they don't fit on one.
+fn ts_content(fields: &[(&str, String)]) -> String {
+    match fields {
+        [] => "null".to_string(),
+        [(_, ty)] => ty.clone(),
+        fields => {
+            let elements: Vec<_> = fields
+                .iter()
+                .map(|(name, ty)| format!("{}: {ty}", camel_case(name)))
+                .collect();
+            let line = format!("[{}]", elements.join(", "));
+            if "  content: ;".len() + line.len() <= 80 {
+                return line;
             }
-            NGMessage::RequestSubmittingState => {}
+            let lines: String = elements
+                .iter()
+                .map(|element| format!("\n    {element},"))
+                .collect();
+            format!("[{lines}\n  ]")
         }
+    }
+}
+
+// Type ids are part of the protocol, so never reuse or renumber them.
+messages! {
+    0 => StateSubmitting(epoch: Epoch, theme: Option<String>),
+    1 => SubmitName(name: String),
+    2 => NameSubmitted(name: String, id: Uuid),
+    3 => UnsubmitName(id: Uuid),
+    4 => NameUnsubmitted(id: Uuid),
+    5 => NumNames(count: usize),
+    6 => RequestPlayingState,
+    /// Names with bitmaps of which are guessed and which are duplicates.
+    7 => Names(names: Vec<String>, guesses: Bitmap, duplicates: Bitmap),
+    8 => GuessName(index: usize),
+    9 => NameGuessed(index: usize),
+    10 => UnguessName(index: usize),
+    11 => NameUnguessed(index: usize),
+    /// Starts a new round, optionally with a theme for the names.
+    12 => RequestSubmittingState(theme: Option<String>),
+    13 => JoinCode(code: RoomCode),
+    14 => RequestRevealState,
+    15 => Authors(authors: Vec<String>),
+    16 => GuessNameBy(index: usize, player: PlayerId),
+    17 => Scoreboard(scores: Vec<Score>),
+    18 => PlayerToken(token: String),
+    19 => Error {
+        code: ErrorCode,
+        message: String,
+        /// Type of the message that was rejected, if the error is a reply.
+        in_reply_to: Option<u32>,
+    },
+    20 => HostToken(token: String),
+    /// Starts a new round themed with a prompt from the deck.
+    21 => DrawPrompt,
+    /// Sets how many names each player may submit per round, zero meaning
+    /// no limit.
+    22 => SetNameLimit(limit: u32),
+    /// How many more names the player may submit this round, if limited.
+    23 => NameQuota(quota: Option<u32>),
+    24 => SetDuplicatePolicy(policy: DuplicatePolicy),
+    /// Names waiting for the host's approval, sent to the host display only.
+    25 => PendingNames(names: Vec<(String, Uuid)>),
+    26 => ApproveName(id: Uuid),
+    27 => RejectName(id: Uuid),
+    28 => RemoveName(name: NameRef),
+    /// Turns the host display's preview of submitted names on or off.
+    29 => SetPreview(enabled: bool),
+    /// First message of every connection. Capabilities are plain strings, so
+    /// that newer clients can ask for features this server doesn't know.
+    30 => Hello {
+        protocol_version: u32,
+        client_kind: ClientKind,
+        capabilities: Vec<String>,
+    },
+    /// Reply to `Hello`, with the version and capabilities both sides share.
+    31 => Welcome {
+        protocol_version: u32,
+        capabilities: Vec<Capability>,
+    },
+    /// How many players are connected and answering, sent to displays.
+    32 => ConnectedPlayers(count: usize),
+    /// Who's connected, sent to displays whenever someone joins, leaves or
+    /// submits a name.
+    33 => Roster(players: Vec<RosterEntry>),
+    /// The player's name is waiting for the host's approval, and is left out
+    /// of the game unless approved.
+    34 => NameHeld(id: Uuid),
+    /// The host approved the player's held name.
+    35 => NameApproved(id: Uuid),
+}
 
-        Bytes::from(encoded)
+impl NGMessage {
+    /// Lists the names, flagging any duplicates among them.
+    pub fn names(names: Vec<String>, guesses: Vec<u8>) -> Self {
+        let duplicates = duplicate_flags(&names);
+        NGMessage::Names(names, Bitmap(guesses), Bitmap(duplicates))
+    }
+
+    /// Rejects a request of type `in_reply_to`.
+    pub fn error(code: ErrorCode, message: impl Into<String>, in_reply_to: u32) -> Self {
+        NGMessage::Error {
+            code,
+            message: message.into(),
+            in_reply_to: Some(in_reply_to),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_round_trip() {
+        let id = Uuid::new_v4();
+        let message = NGMessage::NameSubmitted("Ada".to_string(), id);
+        let parsed = NGMessage::parse(message.encode()).unwrap();
+        assert!(
+            matches!(parsed, NGMessage::NameSubmitted(name, other) if name == "Ada" && other == id)
+        );
+
+        // a nil payload is left out
+        let encoded = NGMessage::RequestPlayingState.encode();
+        assert_eq!(&encoded[..], 6u32.to_be_bytes());
+        assert!(matches!(
+            NGMessage::parse(encoded).unwrap(),
+            NGMessage::RequestPlayingState
+        ));
+    }
+
+    // the shapes the README's scripting section describes
+    #[test]
+    fn json_differs_from_the_schema_as_documented() {
+        let id = Uuid::nil();
+        let score = Score {
+            player: crate::PlayerId(id),
+            nickname: "Ann".to_string(),
+            round_points: 2,
+            total_points: 5,
+        };
+        let json: serde_json::Value =
+            serde_json::from_str(&NGMessage::Scoreboard(vec![score]).encode_json()).unwrap();
+        assert_eq!(
+            json["content"],
+            serde_json::json!([{
+                "player": id.to_string(),
+                "nickname": "Ann",
+                "round_points": 2,
+                "total_points": 5,
+            }])
+        );
+
+        let names = NGMessage::Names(vec![], Bitmap(vec![0b1000_0000]), Bitmap(vec![0]));
+        let json: serde_json::Value = serde_json::from_str(&names.encode_json()).unwrap();
+        assert_eq!(json["content"], serde_json::json!([[], [128], [0]]));
+    }
+
+    #[test]
+    fn truncated_messages_are_rejected() {
+        assert!(NGMessage::parse(Bytes::new()).is_err());
+        assert!(NGMessage::parse(Bytes::from_static(&[0, 0, 0])).is_err());
+
+        let encoded = NGMessage::NameSubmitted("Ada".to_string(), Uuid::new_v4()).encode();
+        for len in 4..encoded.len() {
+            assert!(
+                NGMessage::parse(encoded.slice(..len)).is_err(),
+                "parsed message cut off after {len} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_types_are_rejected() {
+        assert!(NGMessage::parse(Bytes::from_static(&[0, 0, 255, 255])).is_err());
+        assert!(NGMessage::parse_json(r#"{"type":"Nonsense"}"#).is_err());
+    }
+
+    #[test]
+    fn frontend_schema_is_up_to_date() {
+        assert_eq!(
+            ts_schema(),
+            include_str!("../../frontend/src/lib/schema.ts"),
+            "run `npm run schema` in frontend/ to regenerate it"
+        );
     }
 }
@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+
+use unicode_normalization::{UnicodeNormalization, char::is_combining_mark};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::messages::ErrorCode;
+
+/// Longest name, in user-perceived characters, that players can submit.
+pub const MAX_NAME_GRAPHEMES: usize = 64;
+
+/// Longest nickname, in user-perceived characters. Longer ones are cut short.
+pub const MAX_NICKNAME_GRAPHEMES: usize = 32;
+
+/// Characters that don't show up at all, which we drop rather than reject
+/// since they're mostly pasted in by accident. Joiners stay, as emoji need
+/// them.
+const INVISIBLE: [char; 3] = ['\u{200B}', '\u{2060}', '\u{FEFF}'];
+
+/// Why a submitted name was rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NameError {
+    Empty,
+    TooLong,
+    InvalidCharacters,
+}
+
+impl NameError {
+    pub fn code(self) -> ErrorCode {
+        match self {
+            NameError::Empty => ErrorCode::EmptyName,
+            NameError::TooLong => ErrorCode::NameTooLong,
+            NameError::InvalidCharacters => ErrorCode::InvalidCharacters,
+        }
+    }
+
+    pub fn message(self) -> String {
+        match self {
+            NameError::Empty => "names can't be empty".to_string(),
+            NameError::TooLong => {
+                format!("names can be at most {MAX_NAME_GRAPHEMES} characters long")
+            }
+            NameError::InvalidCharacters => "names can't contain control characters".to_string(),
+        }
+    }
+}
+
+/// Cleans up a submitted name so it displays the same way everywhere: NFC
+/// normalized, with invisible characters dropped and runs of whitespace
+/// collapsed into single spaces.
+pub fn normalize_name(name: &str) -> Result<String, NameError> {
+    let name: String = name.nfc().filter(|c| !INVISIBLE.contains(c)).collect();
+    // whitespace like tabs and newlines counts as control characters too, but
+    // gets collapsed below instead
+    if name
+        .chars()
+        .any(|c| !c.is_whitespace() && (c.is_control() || is_bidi_control(c)))
+    {
+        return Err(NameError::InvalidCharacters);
+    }
+
+    let name = collapse_whitespace(&name);
+    if name.is_empty() {
+        return Err(NameError::Empty);
+    }
+    if name.graphemes(true).count() > MAX_NAME_GRAPHEMES {
+        return Err(NameError::TooLong);
+    }
+    Ok(name)
+}
+
+/// Cleans up a nickname like a name, except that control characters are
+/// dropped and long nicknames cut short rather than refused, since they come
+/// from the join link. Returns `None` if nothing is left.
+pub fn normalize_nickname(nickname: &str) -> Option<String> {
+    let nickname: String = nickname
+        .nfc()
+        .filter(|&c| !INVISIBLE.contains(&c) && !is_bidi_control(c))
+        .filter(|&c| c.is_whitespace() || !c.is_control())
+        .collect();
+    let nickname: String = collapse_whitespace(&nickname)
+        .graphemes(true)
+        .take(MAX_NICKNAME_GRAPHEMES)
+        .collect();
+    let nickname = nickname.trim_end();
+    (!nickname.is_empty()).then(|| nickname.to_string())
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Characters that reorder the text around them, which could mess with the
+/// rest of the display.
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+/// Key under which names count as duplicates of each other: lowercased, with
+/// accents stripped.
+pub fn dedup_key(name: &str) -> String {
+    name.nfd()
+        .filter(|&c| !is_combining_mark(c))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Bitmap of which names have a duplicate in the list, laid out like the
+/// guesses in `Names`.
+pub fn duplicate_flags(names: &[String]) -> Vec<u8> {
+    let keys: Vec<_> = names.iter().map(|name| dedup_key(name)).collect();
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for key in &keys {
+        *counts.entry(key).or_default() += 1;
+    }
+    let mut flags = vec![0; keys.len().div_ceil(8)];
+    for (index, key) in keys.iter().enumerate() {
+        if counts[key.as_str()] > 1 {
+            flags[index / 8] |= 0x80 >> (index % 8);
+        }
+    }
+    flags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_cleaned_up() {
+        assert_eq!(
+            normalize_name("  Ada \t Lovelace\n").unwrap(),
+            "Ada Lovelace"
+        );
+        assert_eq!(normalize_name("Ze\u{301}no").unwrap(), "Z\u{e9}no");
+        assert_eq!(normalize_name("Ad\u{200B}a\u{FEFF}").unwrap(), "Ada");
+        // joiners hold emoji together
+        assert_eq!(normalize_name("👩\u{200D}🚀").unwrap(), "👩\u{200D}🚀");
+    }
+
+    #[test]
+    fn bad_names_are_rejected() {
+        assert_eq!(normalize_name(" \u{200B} "), Err(NameError::Empty));
+        assert_eq!(
+            normalize_name("Ada\u{7}"),
+            Err(NameError::InvalidCharacters)
+        );
+        assert_eq!(
+            normalize_name("\u{202E}adA"),
+            Err(NameError::InvalidCharacters)
+        );
+        assert_eq!(
+            normalize_name(&"a".repeat(MAX_NAME_GRAPHEMES + 1)),
+            Err(NameError::TooLong)
+        );
+    }
+
+    #[test]
+    fn length_counts_graphemes() {
+        let name = "e\u{301}".repeat(MAX_NAME_GRAPHEMES);
+        assert!(normalize_name(&name).is_ok());
+        let name = "👩\u{200D}🚀".repeat(MAX_NAME_GRAPHEMES);
+        assert!(normalize_name(&name).is_ok());
+    }
+
+    #[test]
+    fn nicknames_are_cleaned_up() {
+        assert_eq!(normalize_nickname("  Ann \n").as_deref(), Some("Ann"));
+        assert_eq!(
+            normalize_nickname("\u{202E}nn\u{7}A\u{200B}").as_deref(),
+            Some("nnA")
+        );
+        assert_eq!(normalize_nickname(" \u{200B}\u{202E} "), None);
+    }
+
+    #[test]
+    fn long_nicknames_are_cut_between_graphemes() {
+        let nickname = "e\u{301}".repeat(MAX_NICKNAME_GRAPHEMES + 1);
+        assert_eq!(
+            normalize_nickname(&nickname).unwrap(),
+            "\u{e9}".repeat(MAX_NICKNAME_GRAPHEMES)
+        );
+        let nickname = "👩\u{200D}🚀".repeat(MAX_NICKNAME_GRAPHEMES + 1);
+        assert_eq!(
+            normalize_nickname(&nickname).unwrap(),
+            "👩\u{200D}🚀".repeat(MAX_NICKNAME_GRAPHEMES)
+        );
+        // without leaving a space at the end
+        let nickname = format!("{} Bo", "a".repeat(MAX_NICKNAME_GRAPHEMES - 1));
+        assert_eq!(
+            normalize_nickname(&nickname).unwrap(),
+            "a".repeat(MAX_NICKNAME_GRAPHEMES - 1)
+        );
+    }
+
+    #[test]
+    fn dedup_key_ignores_case_and_accents() {
+        assert_eq!(dedup_key("Zoë"), dedup_key("ZOE"));
+        assert_eq!(dedup_key("Ze\u{301}no"), dedup_key("zeno"));
+        assert_ne!(dedup_key("Ada"), dedup_key("Adam"));
+    }
+
+    #[test]
+    fn duplicates_are_flagged() {
+        let names: Vec<_> = ["Ada", "Bea", "ADA", "Cy"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(duplicate_flags(&names), [0b1010_0000]);
+    }
+}
@@ -1,39 +1,171 @@
-use std::{pin::pin, sync::Arc};
+use std::{collections::HashSet, pin::pin, sync::Arc};
 
 use futures::stream::unfold;
 use tokio_stream::StreamExt;
-use tracing::error;
+use tracing::{error, warn};
+use uuid::Uuid;
 
 use crate::{
-    GameState,
-    messages::NGMessage,
-    redis_wrapper::RedisWrapper,
-    socket::{Sender, Socket},
+    GameState, Player, PlayerId,
+    filter::{ContentFilter, Verdict},
+    messages::{Capability, ClientKind, ErrorCode, NGMessage},
+    names::normalize_name,
+    socket::{ConnectionError, Liveness, Receiver, Sender, Session, Socket, handshake},
+    store::{AddedName, DuplicatePolicy, GameStore, GuessedName, RemovedName, RoomDefaults},
 };
 
 enum Event {
-    Message(miette::Result<Option<NGMessage>>),
+    Message(Result<Option<NGMessage>, ConnectionError>),
     StateChange(GameState),
     NameGuessed(usize),
     NameUnguessed(usize),
+    ScoresChanged,
+    NameRejected(Uuid, PlayerId),
+    NameAccepted(Uuid),
+    NamesChanged,
+    NameLimitChanged,
+    Heartbeat,
+    Resync,
 }
 
-async fn send_state(state: GameState, socket: &mut Sender, redis_wrapper: &RedisWrapper) {
+async fn send_quota(
+    socket: &mut Sender,
+    session: &Session,
+    room: &dyn GameStore,
+    player: &Player,
+    defaults: &RoomDefaults,
+) -> Result<(), ConnectionError> {
+    if !session.supports(Capability::NameQuota) {
+        return Ok(());
+    }
+    let quota = match room.effective_name_limit(defaults).await? {
+        Some(limit) => {
+            let submitted = room.player_names(&player.id).await?.len();
+            Some(limit.saturating_sub(submitted as u32))
+        }
+        None => None,
+    };
+    socket.send(NGMessage::NameQuota(quota)).await
+}
+
+/// Sends the room's state, and keeps track of which of the player's names
+/// are held for approval in `held`.
+async fn send_state(
+    state: GameState,
+    socket: &mut Sender,
+    session: &Session,
+    room: &dyn GameStore,
+    player: &Player,
+    held: &mut HashSet<Uuid>,
+    defaults: &RoomDefaults,
+) -> Result<(), ConnectionError> {
+    held.clear();
     match state {
-        GameState::Submitting(epoch) => socket
-            .send(NGMessage::StateSubmitting(epoch))
-            .await
-            .unwrap(),
+        GameState::Submitting(epoch) => {
+            let theme = room.theme().await?;
+            socket
+                .send(NGMessage::StateSubmitting(epoch, theme))
+                .await?;
+            // the player may have submitted names from an earlier connection
+            let pending = room.pending_names().await?;
+            for (name, id) in room.player_names(&player.id).await? {
+                socket.send(NGMessage::NameSubmitted(name, id)).await?;
+                if pending.iter().any(|(_, other)| *other == id) {
+                    held.insert(id);
+                    socket.send(NGMessage::NameHeld(id)).await?;
+                }
+            }
+            send_quota(socket, session, room, player, defaults).await
+        }
         GameState::Playing => {
-            let (names, guesses) = redis_wrapper.names_and_guesses().await.unwrap();
-            socket.send(NGMessage::Names(names, guesses)).await.unwrap()
+            let (names, guesses) = room.names_and_guesses().await?;
+            socket.send(NGMessage::names(names, guesses)).await
+        }
+        GameState::Revealing => {
+            let (names, guesses) = room.names_and_guesses().await?;
+            socket.send(NGMessage::names(names, guesses)).await?;
+            let authors = room.authors().await?;
+            socket.send(NGMessage::Authors(authors)).await
         }
     }
 }
 
-pub async fn handle_player(socket: Socket, redis_wrapper: Arc<RedisWrapper>) {
+/// Sends everything a player needs to show the room as it is now.
+async fn send_snapshot(
+    socket: &mut Sender,
+    session: &Session,
+    room: &dyn GameStore,
+    player: &Player,
+    held: &mut HashSet<Uuid>,
+    defaults: &RoomDefaults,
+) -> Result<(), ConnectionError> {
+    let state = room.state();
+    send_state(state, socket, session, room, player, held, defaults).await?;
+    let scores = room.scoreboard().await?;
+    socket.send(NGMessage::Scoreboard(scores)).await
+}
+
+pub async fn handle_player(
+    socket: Socket,
+    room: Arc<dyn GameStore>,
+    player: Player,
+    token: String,
+    filter: Arc<ContentFilter>,
+    defaults: RoomDefaults,
+) {
     let (mut socket_sender, socket_receiver) = socket.split();
-    send_state(redis_wrapper.state(), &mut socket_sender, &redis_wrapper).await;
+    let connection = socket_sender.id();
+    let result = serve_player(
+        &mut socket_sender,
+        socket_receiver,
+        room.clone(),
+        player,
+        token,
+        &filter,
+        &defaults,
+    )
+    .await;
+    socket_sender.close(result).await;
+    if let Err(err) = room.remove_connection(&connection).await {
+        error!(
+            "couldn't remove connection from room {}: {err:?}",
+            room.code()
+        );
+    }
+    // keeps whatever the player changed since the last heartbeat from
+    // outliving the room
+    if let Err(err) = room.touch().await {
+        error!("couldn't touch room {}: {err:?}", room.code());
+    }
+}
+
+async fn serve_player(
+    socket_sender: &mut Sender,
+    mut socket_receiver: Receiver,
+    room: Arc<dyn GameStore>,
+    player: Player,
+    token: String,
+    filter: &ContentFilter,
+    defaults: &RoomDefaults,
+) -> Result<(), ConnectionError> {
+    let session = handshake(socket_sender, &mut socket_receiver, ClientKind::Player).await?;
+    room.touch().await?;
+    room.set_nickname(&player).await?;
+    let connection = socket_sender.id();
+    let ttl = socket_sender.idle_timeout();
+    room.add_connection(&connection, &player.id, ttl).await?;
+    socket_sender.send(NGMessage::PlayerToken(token)).await?;
+    // the player's names waiting for the host's approval
+    let mut held = HashSet::new();
+    send_snapshot(
+        socket_sender,
+        &session,
+        room.as_ref(),
+        &player,
+        &mut held,
+        defaults,
+    )
+    .await?;
 
     let a = unfold(socket_receiver, async |mut socket_receiver| {
         Some((
@@ -41,62 +173,373 @@ pub async fn handle_player(socket: Socket, redis_wrapper: Arc<RedisWrapper>) {
             socket_receiver,
         ))
     });
-    let b = redis_wrapper.state_change_stream().map(Event::StateChange);
-    let c = redis_wrapper.guess_stream().map(Event::NameGuessed);
-    let d = redis_wrapper.unguess_stream().map(Event::NameUnguessed);
-    let mut stream = pin!(a.merge(b).merge(c).merge(d));
+    let b = room.state_change_stream().map(Event::StateChange);
+    let c = room.guess_stream().map(Event::NameGuessed);
+    let d = room.unguess_stream().map(Event::NameUnguessed);
+    let e = room.scoreboard_stream().map(|()| Event::ScoresChanged);
+    let f = room.resync_stream().map(|()| Event::Resync);
+    let g = room
+        .rejection_stream()
+        .map(|(id, author)| Event::NameRejected(id, author));
+    let h = room.names_changed_stream().map(|()| Event::NamesChanged);
+    let i = room.name_limit_stream().map(|()| Event::NameLimitChanged);
+    let j = room
+        .submission_stream()
+        .map(|(_, id)| Event::NameAccepted(id));
+    let k = socket_sender.heartbeats().map(|()| Event::Heartbeat);
+    let mut stream = pin!(
+        a.merge(b)
+            .merge(c)
+            .merge(d)
+            .merge(e)
+            .merge(f)
+            .merge(g)
+            .merge(h)
+            .merge(i)
+            .merge(j)
+            .merge(k)
+    );
 
     while let Some(event) = stream.next().await {
         match event {
             Event::Message(msg) => {
                 let msg = match msg {
-                    Ok(Some(msg)) => msg,
-                    Ok(None) => break,
-                    Err(err) => {
-                        error!("error while receiving message from player: {err:?}");
-                        break;
+                    Err(ConnectionError::Throttled(msg_type)) => {
+                        socket_sender
+                            .send(NGMessage::error(
+                                ErrorCode::RateLimited,
+                                "you're sending messages too fast",
+                                msg_type,
+                            ))
+                            .await?;
+                        continue;
                     }
+                    msg => msg?,
                 };
-                match msg {
-                    NGMessage::SubmitName(name)
-                        if matches!(redis_wrapper.state(), GameState::Submitting(_)) =>
+                let Some(msg) = msg else {
+                    break;
+                };
+                let msg_type = msg.type_id();
+                let reply = match msg {
+                    NGMessage::SubmitName(_) | NGMessage::UnsubmitName(_)
+                        if !matches!(room.state(), GameState::Submitting(_)) =>
                     {
-                        let id = redis_wrapper.add_name(&name).await.unwrap();
-                        socket_sender
-                            .send(NGMessage::NameSubmitted(name, id))
-                            .await
-                            .unwrap()
+                        NGMessage::error(
+                            ErrorCode::WrongState,
+                            "names can only be changed before the game starts",
+                            msg_type,
+                        )
                     }
-                    NGMessage::UnsubmitName(id)
-                        if matches!(redis_wrapper.state(), GameState::Submitting(_)) =>
-                    {
-                        redis_wrapper.remove_name(&id).await.unwrap();
-                        socket_sender
-                            .send(NGMessage::NameUnsubmitted(id))
-                            .await
-                            .unwrap()
+                    NGMessage::SubmitName(name) => {
+                        let name = match normalize_name(&name) {
+                            Ok(name) => name,
+                            Err(err) => {
+                                socket_sender
+                                    .send(NGMessage::error(err.code(), err.message(), msg_type))
+                                    .await?;
+                                continue;
+                            }
+                        };
+                        let (name, hold) = match filter.check(name) {
+                            Verdict::Allowed(name) => (name, false),
+                            Verdict::Held(name) => (name, true),
+                            Verdict::Rejected => {
+                                socket_sender
+                                    .send(NGMessage::error(
+                                        ErrorCode::InappropriateName,
+                                        "that name isn't allowed",
+                                        msg_type,
+                                    ))
+                                    .await?;
+                                continue;
+                            }
+                        };
+                        let limit = room.effective_name_limit(defaults).await?;
+                        let policy = room.effective_duplicate_policy(defaults).await?;
+                        let reply = match room
+                            .add_name(
+                                &name,
+                                &player.id,
+                                limit,
+                                policy == DuplicatePolicy::Reject,
+                                hold,
+                            )
+                            .await?
+                        {
+                            AddedName::Added(id) if hold => {
+                                socket_sender
+                                    .send(NGMessage::NameSubmitted(name, id))
+                                    .await?;
+                                held.insert(id);
+                                NGMessage::NameHeld(id)
+                            }
+                            AddedName::Added(id) => NGMessage::NameSubmitted(name, id),
+                            AddedName::LimitReached => NGMessage::error(
+                                ErrorCode::NameLimitReached,
+                                "you can't submit any more names this round",
+                                msg_type,
+                            ),
+                            AddedName::Duplicate => NGMessage::error(
+                                ErrorCode::DuplicateName,
+                                "somebody already submitted that name",
+                                msg_type,
+                            ),
+                        };
+                        socket_sender.send(reply).await?;
+                        send_quota(socket_sender, &session, room.as_ref(), &player, defaults)
+                            .await?;
+                        continue;
+                    }
+                    NGMessage::UnsubmitName(id) => match room.remove_name(&id, &player.id).await? {
+                        RemovedName::Removed => {
+                            socket_sender.send(NGMessage::NameUnsubmitted(id)).await?;
+                            send_quota(socket_sender, &session, room.as_ref(), &player, defaults)
+                                .await?;
+                            continue;
+                        }
+                        RemovedName::NotAuthor => {
+                            warn!("player tried to unsubmit somebody else's name");
+                            NGMessage::error(
+                                ErrorCode::Unauthorized,
+                                "you can only unsubmit your own names",
+                                msg_type,
+                            )
+                        }
+                        RemovedName::NoSuchName => {
+                            NGMessage::error(ErrorCode::NoSuchName, "no such name", msg_type)
+                        }
+                    },
+                    NGMessage::GuessName(_) if room.state() != GameState::Playing => {
+                        NGMessage::error(
+                            ErrorCode::WrongState,
+                            "names can only be guessed while playing",
+                            msg_type,
+                        )
+                    }
+                    NGMessage::GuessName(index) if index >= room.name_count() => {
+                        NGMessage::error(ErrorCode::InvalidIndex, "no such name", msg_type)
+                    }
+                    NGMessage::GuessName(index) => {
+                        match room.guess_name(index, Some(&player.id), false).await? {
+                            GuessedName::Guessed => continue,
+                            GuessedName::OwnName => {
+                                warn!("player tried to guess their own name");
+                                NGMessage::error(
+                                    ErrorCode::Unauthorized,
+                                    "you can't guess your own name",
+                                    msg_type,
+                                )
+                            }
+                            GuessedName::AlreadyGuessed => NGMessage::error(
+                                ErrorCode::AlreadyGuessed,
+                                "somebody already guessed that name",
+                                msg_type,
+                            ),
+                        }
                     }
                     _ => {
-                        error!("unexpected message from player: {msg:?}");
-                        break;
+                        warn!("unexpected message from player: {msg:?}");
+                        NGMessage::error(
+                            ErrorCode::UnexpectedMessage,
+                            "players can't send this message",
+                            msg_type,
+                        )
                     }
-                }
+                };
+                socket_sender.send(reply).await?;
+            }
+            Event::Resync => {
+                send_snapshot(
+                    socket_sender,
+                    &session,
+                    room.as_ref(),
+                    &player,
+                    &mut held,
+                    defaults,
+                )
+                .await?
             }
             Event::StateChange(new_state) => {
-                send_state(new_state, &mut socket_sender, &redis_wrapper).await;
+                send_state(
+                    new_state,
+                    socket_sender,
+                    &session,
+                    room.as_ref(),
+                    &player,
+                    &mut held,
+                    defaults,
+                )
+                .await?;
             }
             Event::NameGuessed(index) => {
-                socket_sender
-                    .send(NGMessage::NameGuessed(index))
-                    .await
-                    .unwrap();
+                socket_sender.send(NGMessage::NameGuessed(index)).await?;
             }
             Event::NameUnguessed(index) => {
-                socket_sender
-                    .send(NGMessage::NameUnguessed(index))
-                    .await
-                    .unwrap();
+                socket_sender.send(NGMessage::NameUnguessed(index)).await?;
+            }
+            Event::ScoresChanged => {
+                let scores = room.scoreboard().await?;
+                socket_sender.send(NGMessage::Scoreboard(scores)).await?;
+            }
+            Event::NameRejected(id, author) if author == player.id => {
+                held.remove(&id);
+                socket_sender.send(NGMessage::NameUnsubmitted(id)).await?;
+                send_quota(socket_sender, &session, room.as_ref(), &player, defaults).await?;
+            }
+            Event::NameRejected(_, _) => {}
+            Event::NameAccepted(id) if held.remove(&id) => {
+                socket_sender.send(NGMessage::NameApproved(id)).await?;
+            }
+            Event::NameAccepted(_) => {}
+            Event::NamesChanged => {
+                let (names, guesses) = room.names_and_guesses().await?;
+                socket_sender.send(NGMessage::names(names, guesses)).await?;
+            }
+            // the quota only means something while names can be submitted
+            Event::NameLimitChanged if matches!(room.state(), GameState::Submitting(_)) => {
+                send_quota(socket_sender, &session, room.as_ref(), &player, defaults).await?;
+            }
+            Event::NameLimitChanged => {}
+            // live connections are refreshed before they expire, while
+            // unresponsive players stop counting as connected until they
+            // answer again
+            Event::Heartbeat => {
+                match socket_sender.heartbeat().await? {
+                    Liveness::Alive => room.add_connection(&connection, &player.id, ttl).await?,
+                    Liveness::Unresponsive => room.remove_connection(&connection).await?,
+                }
+                room.touch().await?;
             }
         }
     }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{memory_store::MemoryStore, store::RoomStore};
+
+    #[tokio::test]
+    async fn players_see_their_names_and_quota() {
+        let store = MemoryStore::new(Duration::from_secs(60));
+        let room = store.create_room().await.unwrap();
+        let player = Player::new(PlayerId::new(), Some("Ann".to_string()));
+        let defaults = RoomDefaults {
+            name_limit: Some(2),
+            ..RoomDefaults::default()
+        };
+        let (socket, mut client) = Socket::in_process();
+        let handler = tokio::spawn(handle_player(
+            socket,
+            room.clone(),
+            player.clone(),
+            "token".to_string(),
+            Arc::default(),
+            defaults,
+        ));
+        client.hello(ClientKind::Player, &[Capability::NameQuota]);
+        let token = client
+            .find(|message| match message {
+                NGMessage::PlayerToken(token) => Some(token),
+                _ => None,
+            })
+            .await;
+        assert_eq!(token, "token");
+        let quota = |message| match message {
+            NGMessage::NameQuota(quota) => Some(quota),
+            _ => None,
+        };
+        assert_eq!(client.find(quota).await, Some(2));
+
+        client.send(NGMessage::SubmitName("Ada".to_string()));
+        let id = client
+            .find(|message| match message {
+                NGMessage::NameSubmitted(name, id) if name == "Ada" => Some(id),
+                _ => None,
+            })
+            .await;
+        assert_eq!(client.find(quota).await, Some(1));
+        assert_eq!(
+            room.player_names(&player.id).await.unwrap(),
+            [("Ada".to_string(), id)]
+        );
+
+        drop(client);
+        handler.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn players_hear_when_the_limit_changes() {
+        let store = MemoryStore::new(Duration::from_secs(60));
+        let room = store.create_room().await.unwrap();
+        let player = Player::new(PlayerId::new(), Some("Ann".to_string()));
+        let (socket, mut client) = Socket::in_process();
+        let handler = tokio::spawn(handle_player(
+            socket,
+            room.clone(),
+            player,
+            "token".to_string(),
+            Arc::default(),
+            RoomDefaults::default(),
+        ));
+        client.hello(ClientKind::Player, &[Capability::NameQuota]);
+        let quota = |message| match message {
+            NGMessage::NameQuota(quota) => Some(quota),
+            _ => None,
+        };
+        assert_eq!(client.find(quota).await, None);
+
+        room.set_name_limit(3).await.unwrap();
+        assert_eq!(client.find(quota).await, Some(3));
+
+        drop(client);
+        handler.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn players_see_their_names_wait_for_approval() {
+        let store = MemoryStore::new(Duration::from_secs(60));
+        let room = store.create_room().await.unwrap();
+        let player = Player::new(PlayerId::new(), Some("Ann".to_string()));
+        // held while the player was away
+        let AddedName::Added(id) = room
+            .add_name("Ada", &player.id, None, false, true)
+            .await
+            .unwrap()
+        else {
+            panic!("couldn't hold name");
+        };
+        let (socket, mut client) = Socket::in_process();
+        let handler = tokio::spawn(handle_player(
+            socket,
+            room.clone(),
+            player,
+            "token".to_string(),
+            Arc::default(),
+            RoomDefaults::default(),
+        ));
+        client.hello(ClientKind::Player, &[]);
+        let held = client
+            .find(|message| match message {
+                NGMessage::NameHeld(id) => Some(id),
+                _ => None,
+            })
+            .await;
+        assert_eq!(held, id);
+
+        assert!(room.approve_name(&id).await.unwrap());
+        let approved = client
+            .find(|message| match message {
+                NGMessage::NameApproved(id) => Some(id),
+                _ => None,
+            })
+            .await;
+        assert_eq!(approved, id);
+
+        drop(client);
+        handler.await.unwrap();
+    }
 }
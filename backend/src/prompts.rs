@@ -0,0 +1,34 @@
+use std::path::Path;
+
+use miette::Context;
+use rand::{rng, seq::SliceRandom};
+
+use crate::{settings::load_file, store::GameStore};
+
+/// Prompts the host can draw from to theme a round, in the spirit of the Game
+/// of Things.
+#[derive(serde::Deserialize, Debug, Default)]
+pub struct PromptDeck {
+    prompts: Vec<String>,
+}
+
+impl PromptDeck {
+    /// Loads a deck from a file with a `prompts` list.
+    pub fn load(path: &Path) -> miette::Result<Self> {
+        load_file(path).wrap_err("load prompt deck")
+    }
+
+    /// Picks a random prompt that hasn't been used in the room yet and marks it
+    /// as used, or returns `None` once the deck runs out.
+    pub async fn draw(&self, room: &dyn GameStore) -> miette::Result<Option<&str>> {
+        let mut order: Vec<_> = (0..self.prompts.len()).collect();
+        order.shuffle(&mut rng());
+        for index in order {
+            let prompt = &self.prompts[index];
+            if room.claim_prompt(prompt).await? {
+                return Ok(Some(prompt));
+            }
+        }
+        Ok(None)
+    }
+}
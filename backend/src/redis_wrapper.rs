@@ -1,312 +1,1385 @@
 use std::{
+    collections::{HashMap, HashSet},
     error::Error,
     marker::{Send, Sync},
     str::FromStr,
-    sync::LazyLock,
+    sync::{Arc, LazyLock, Mutex, Weak},
+    time::Duration,
 };
 
-use futures::{Stream, StreamExt};
-use miette::{Context, IntoDiagnostic, bail};
+use async_trait::async_trait;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use miette::{Context, IntoDiagnostic, bail, miette};
 use rand::{Rng, rng};
 use redis::{
     AsyncConnectionConfig, AsyncTypedCommands, Client, PushKind, Script, Value,
-    aio::MultiplexedConnection,
+    aio::{ConnectionManager, ConnectionManagerConfig, MultiplexedConnection},
 };
 use secrecy::{ExposeSecret, SecretString};
-use tokio::sync::{broadcast::Receiver as BroadcastReceiver, watch::Receiver as WatchReceiver};
-use tokio_stream::wrappers::{BroadcastStream, WatchStream};
-use tracing::warn;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{error, warn};
 use uuid::Uuid;
 
-use crate::{Epoch, GameState};
+use crate::{
+    Epoch, GameState, Player, PlayerId, RoomCode,
+    names::dedup_key,
+    store::{
+        AddedName, CREATE_ROOM_ATTEMPTS, DuplicatePolicy, GameStore, GuessedName, RemovedName,
+        RoomChannels, RoomStore, RosterEntry, Score,
+    },
+};
+
+const ROOM_PREFIX: &str = "room";
+const TOKEN_SECRET_KEY: &str = "tokenSecret";
+const ROOM_CHANNEL_PATTERN: &str = "room:*";
+const RECONNECT_MIN_DELAY: Duration = Duration::from_millis(100);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
 
 const NAMES_KEY: &str = "names";
 const GUESSES_KEY: &str = "guesses";
 const STATE_KEY: &str = "gameState";
 const EPOCH_KEY: &str = "epoch";
+const THEME_KEY: &str = "theme";
+const USED_PROMPTS_KEY: &str = "usedPrompts";
+const NAME_LIMIT_KEY: &str = "nameLimit";
+const DUPLICATE_POLICY_KEY: &str = "duplicatePolicy";
+const NAME_KEYS_KEY: &str = "nameKeys";
+const PENDING_KEY: &str = "pending";
+const AUTHORS_KEY: &str = "authors";
+const PLAYERS_KEY: &str = "players";
+const CONNECTIONS_KEY: &str = "connections";
+const CONNECTION_EXPIRY_KEY: &str = "connectionExpiry";
+const GUESSERS_KEY: &str = "guessers";
+const ROUND_SCORES_KEY: &str = "roundScores";
+const TOTAL_SCORES_KEY: &str = "totalScores";
+const HOST_NONCE_KEY: &str = "hostNonce";
 
 const NUM_NAMES_CHANNEL: &str = "numNames";
 const GUESS_CHANNEL: &str = "guess";
 const UNGUESS_CHANNEL: &str = "unguess";
+const SCORES_CHANNEL: &str = "scores";
+const PENDING_CHANNEL: &str = "pending";
+const NAME_REJECTED_CHANNEL: &str = "nameRejected";
+const NAMES_CHANGED_CHANNEL: &str = "namesChanged";
+const NAME_SUBMITTED_CHANNEL: &str = "nameSubmitted";
+const NAME_UNSUBMITTED_CHANNEL: &str = "nameUnsubmitted";
+const PRESENCE_CHANNEL: &str = "presence";
+const NAME_LIMIT_CHANNEL: &str = "nameLimit";
 const STATE_SUBMITTING_CHANNEL: &str = "stateSubmitting";
 const STATE_PLAYING_CHANNEL: &str = "statePlaying";
+const STATE_REVEALING_CHANNEL: &str = "stateRevealing";
 
-static ADD_NAME_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+/// Wraps a script that changes a room, so that every key it's given expires
+/// with the room afterwards, even if the script just created it. Room scripts
+/// take the room's channel prefix (e.g. "room:HJKR:") as ARGV[1], and the
+/// room's TTL in seconds as their last argument.
+fn room_script(body: &str) -> Script {
+    Script::new(&format!(
+        r#"
+local function run()
+{body}
+end
+local result = run()
+for _, key in ipairs(KEYS) do
+    server.call("EXPIRE", key, ARGV[#ARGV])
+end
+return result
+"#
+    ))
+}
+
+// takes the room's TTL in seconds as ARGV[1] and every key of the room, so
+// that idle rooms are deleted as a whole
+static TOUCH_ROOM_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
     Script::new(
+        r#"
+for _, key in ipairs(KEYS) do
+    server.call("EXPIRE", key, ARGV[1])
+end
+"#
+        .trim(),
+    )
+});
+
+// takes the state and host nonce keys followed by every key of the room, the
+// room's TTL in seconds as ARGV[1] and its host nonce as ARGV[2]; a code is
+// free once its state expired, but the room's other keys may outlive it for a
+// moment, so they're cleared
+static CREATE_ROOM_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        &r#"
+if server.call("EXISTS", KEYS[1]) == 1 then
+    return 0
+end
+server.call("DEL", unpack(KEYS))
+server.call("SET", KEYS[1], "SUBMITTING_STATE", "EX", ARGV[1])
+server.call("SET", KEYS[2], ARGV[2], "EX", ARGV[1])
+return 1
+"#
+        .trim()
+        .replace("SUBMITTING_STATE", GameState::SUBMITTING),
+    )
+});
+
+// connections expire by the server's clock, so that servers with skewed
+// clocks agree on which ones are stale
+static ADD_CONNECTION_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    room_script(
         &r#"
-server.call("HSET", KEYS[1], ARGV[2], ARGV[1])
+local time = server.call("TIME")
+local expires = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000) + tonumber(ARGV[4])
+server.call("ZADD", KEYS[2], string.format("%d", expires), ARGV[2])
+if server.call("HSET", KEYS[1], ARGV[2], ARGV[3]) > 0 then
+    server.call("PUBLISH", ARGV[1] .. "PRESENCE_CHANNEL", "")
+end
+"#
+        .trim()
+        .replace("PRESENCE_CHANNEL", PRESENCE_CHANNEL),
+    )
+});
+
+static REMOVE_CONNECTION_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    room_script(
+        &r#"
+server.call("ZREM", KEYS[2], ARGV[2])
+if server.call("HDEL", KEYS[1], ARGV[2]) > 0 then
+    server.call("PUBLISH", ARGV[1] .. "PRESENCE_CHANNEL", "")
+end
+"#
+        .trim()
+        .replace("PRESENCE_CHANNEL", PRESENCE_CHANNEL),
+    )
+});
+
+// forgets the connections that weren't refreshed in time, e.g. because their
+// server went away, and returns the players still connected
+static EXPIRE_CONNECTIONS_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    room_script(
+        &r#"
+local time = server.call("TIME")
+local now = string.format("%d", tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000))
+local expired = server.call("ZRANGEBYSCORE", KEYS[2], "-inf", now)
+if #expired > 0 then
+    server.call("ZREMRANGEBYSCORE", KEYS[2], "-inf", now)
+    server.call("HDEL", KEYS[1], unpack(expired))
+    server.call("PUBLISH", ARGV[1] .. "PRESENCE_CHANNEL", "")
+end
+return server.call("HVALS", KEYS[1])
+"#
+        .trim()
+        .replace("PRESENCE_CHANNEL", PRESENCE_CHANNEL),
+    )
+});
+
+static ADD_NAME_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    room_script(
+        &r#"
+-- enforce the per-player limit, if any
+if ARGV[5] ~= "" then
+    local count = 0
+    for _, author in ipairs(server.call("HVALS", KEYS[2])) do
+        if author == ARGV[4] then
+            count = count + 1
+        end
+    end
+    if count >= tonumber(ARGV[5]) then
+        return 1
+    end
+end
+
+-- refuse duplicates, if asked to
+if ARGV[7] == "1" then
+    for _, key in ipairs(server.call("HVALS", KEYS[3])) do
+        if key == ARGV[6] then
+            return 2
+        end
+    end
+end
+
+server.call("HSET", KEYS[1], ARGV[3], ARGV[2])
+server.call("HSET", KEYS[2], ARGV[3], ARGV[4])
+server.call("HSET", KEYS[3], ARGV[3], ARGV[6])
 local num_names = server.call("HLEN", KEYS[1])
-server.call("PUBLISH", "NUM_NAMES_CHANNEL", num_names)
-return ARGV[2]
+server.call("PUBLISH", ARGV[1] .. "NUM_NAMES_CHANNEL", num_names)
+
+-- hold the name for the host's approval, if asked to, or show it in the
+-- host's preview as the 16-byte id followed by the name
+if ARGV[8] == "1" then
+    server.call("RPUSH", KEYS[4], ARGV[3])
+    server.call("PUBLISH", ARGV[1] .. "PENDING_CHANNEL", "")
+else
+    server.call("PUBLISH", ARGV[1] .. "NAME_SUBMITTED_CHANNEL", ARGV[3] .. ARGV[2])
+end
+return 0
 "#
         .trim()
-        .replace("NUM_NAMES_CHANNEL", NUM_NAMES_CHANNEL),
+        .replace("NUM_NAMES_CHANNEL", NUM_NAMES_CHANNEL)
+        .replace("NAME_SUBMITTED_CHANNEL", NAME_SUBMITTED_CHANNEL)
+        .replace("PENDING_CHANNEL", PENDING_CHANNEL),
     )
 });
 
 static REMOVE_NAME_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
-    Script::new(
+    room_script(
         &r#"
-server.call("HDEL", KEYS[1], ARGV[1])
+local author = server.call("HGET", KEYS[2], ARGV[2])
+if not author then
+    return 2
+end
+-- only the author may remove a name, except for the host (an empty author)
+if ARGV[3] ~= "" and author ~= ARGV[3] then
+    return 0
+end
+
+server.call("HDEL", KEYS[1], ARGV[2])
+server.call("HDEL", KEYS[2], ARGV[2])
+server.call("HDEL", KEYS[3], ARGV[2])
 local num_names = server.call("HLEN", KEYS[1])
-server.call("PUBLISH", "NUM_NAMES_CHANNEL", num_names)
+server.call("PUBLISH", ARGV[1] .. "NUM_NAMES_CHANNEL", num_names)
+server.call("PUBLISH", ARGV[1] .. "NAME_UNSUBMITTED_CHANNEL", ARGV[2])
+if server.call("LREM", KEYS[4], 0, ARGV[2]) > 0 then
+    server.call("PUBLISH", ARGV[1] .. "PENDING_CHANNEL", "")
+end
+
+-- tell the author if the host removed their name
+if ARGV[3] == "" then
+    server.call("PUBLISH", ARGV[1] .. "NAME_REJECTED_CHANNEL", ARGV[2] .. author)
+end
+return 1
     "#
         .trim()
-        .replace("NUM_NAMES_CHANNEL", NUM_NAMES_CHANNEL),
+        .replace("NUM_NAMES_CHANNEL", NUM_NAMES_CHANNEL)
+        .replace("NAME_REJECTED_CHANNEL", NAME_REJECTED_CHANNEL)
+        .replace("NAME_UNSUBMITTED_CHANNEL", NAME_UNSUBMITTED_CHANNEL)
+        .replace("PENDING_CHANNEL", PENDING_CHANNEL),
+    )
+});
+
+static REMOVE_NAME_AT_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    room_script(
+        &r#"
+local index = tonumber(ARGV[2])
+local count = server.call("LLEN", KEYS[1])
+if index >= count then
+    return 0
+end
+
+-- take the point back from whoever guessed it
+local previous = server.call("HGET", KEYS[4], ARGV[2])
+if previous then
+    server.call("HINCRBY", KEYS[5], previous, -1)
+    server.call("HINCRBY", KEYS[6], previous, -1)
+    server.call("PUBLISH", ARGV[1] .. "SCORES_CHANNEL", "")
+end
+
+-- shift the later names' guesses and guessers down by one
+for i = index, count - 2 do
+    server.call("SETBIT", KEYS[3], i, server.call("GETBIT", KEYS[3], i + 1))
+    local guesser = server.call("HGET", KEYS[4], i + 1)
+    if guesser then
+        server.call("HSET", KEYS[4], i, guesser)
+    else
+        server.call("HDEL", KEYS[4], i)
+    end
+end
+server.call("SETBIT", KEYS[3], count - 1, 0)
+server.call("HDEL", KEYS[4], count - 1)
+
+-- lists can only remove by value, so swap in a unique marker first
+server.call("LSET", KEYS[1], index, ARGV[3])
+server.call("LREM", KEYS[1], 1, ARGV[3])
+server.call("LSET", KEYS[2], index, ARGV[3])
+server.call("LREM", KEYS[2], 1, ARGV[3])
+
+server.call("PUBLISH", ARGV[1] .. "NUM_NAMES_CHANNEL", count - 1)
+server.call("PUBLISH", ARGV[1] .. "NAMES_CHANGED_CHANNEL", "")
+return 1
+"#
+        .trim()
+        .replace("NUM_NAMES_CHANNEL", NUM_NAMES_CHANNEL)
+        .replace("NAMES_CHANGED_CHANNEL", NAMES_CHANGED_CHANNEL)
+        .replace("SCORES_CHANNEL", SCORES_CHANNEL),
+    )
+});
+
+static APPROVE_NAME_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    room_script(
+        &r#"
+if server.call("LREM", KEYS[1], 0, ARGV[2]) == 0 then
+    return 0
+end
+server.call("PUBLISH", ARGV[1] .. "PENDING_CHANNEL", "")
+local name = server.call("HGET", KEYS[2], ARGV[2])
+if name then
+    server.call("PUBLISH", ARGV[1] .. "NAME_SUBMITTED_CHANNEL", ARGV[2] .. name)
+end
+return 1
+"#
+        .trim()
+        .replace("NAME_SUBMITTED_CHANNEL", NAME_SUBMITTED_CHANNEL)
+        .replace("PENDING_CHANNEL", PENDING_CHANNEL),
+    )
+});
+
+static REJECT_NAME_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    room_script(
+        &r#"
+if server.call("LREM", KEYS[1], 0, ARGV[2]) == 0 then
+    return 0
+end
+
+local author = server.call("HGET", KEYS[3], ARGV[2])
+server.call("HDEL", KEYS[2], ARGV[2])
+server.call("HDEL", KEYS[3], ARGV[2])
+server.call("HDEL", KEYS[4], ARGV[2])
+local num_names = server.call("HLEN", KEYS[2])
+server.call("PUBLISH", ARGV[1] .. "NUM_NAMES_CHANNEL", num_names)
+
+-- tell the author, as the 16-byte name id followed by the 16-byte author id
+if author then
+    server.call("PUBLISH", ARGV[1] .. "NAME_REJECTED_CHANNEL", ARGV[2] .. author)
+end
+server.call("PUBLISH", ARGV[1] .. "PENDING_CHANNEL", "")
+return 1
+"#
+        .trim()
+        .replace("NUM_NAMES_CHANNEL", NUM_NAMES_CHANNEL)
+        .replace("NAME_REJECTED_CHANNEL", NAME_REJECTED_CHANNEL)
+        .replace("PENDING_CHANNEL", PENDING_CHANNEL),
     )
 });
 
 static GUESS_NAME_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
-    Script::new(
+    room_script(
         &r#"
-server.call("SETBIT", KEYS[1], ARGV[1], 1)
-server.call("PUBLISH", "GUESS_CHANNEL", ARGV[1])
+-- nobody gets to guess their own name; merged names have several 16-byte
+-- author ids
+local guesser = ARGV[3]
+if guesser ~= "" then
+    local authors = server.call("LINDEX", KEYS[3], ARGV[2]) or ""
+    for i = 1, #authors, 16 do
+        if string.sub(authors, i, i + 15) == guesser then
+            return 0
+        end
+    end
+end
+
+-- only the host may take a guessed name's point from whoever guessed it
+if ARGV[4] ~= "1" and server.call("GETBIT", KEYS[1], ARGV[2]) == 1 then
+    return 2
+end
+
+server.call("SETBIT", KEYS[1], ARGV[2], 1)
+server.call("PUBLISH", ARGV[1] .. "GUESS_CHANNEL", ARGV[2])
+
+-- move the point from the previous guesser, if any, to the new one
+local previous = server.call("HGET", KEYS[2], ARGV[2])
+if previous == guesser or (not previous and guesser == "") then
+    return 1
+end
+if previous then
+    server.call("HINCRBY", KEYS[4], previous, -1)
+    server.call("HINCRBY", KEYS[5], previous, -1)
+    server.call("HDEL", KEYS[2], ARGV[2])
+end
+if guesser ~= "" then
+    server.call("HSET", KEYS[2], ARGV[2], guesser)
+    server.call("HINCRBY", KEYS[4], guesser, 1)
+    server.call("HINCRBY", KEYS[5], guesser, 1)
+end
+server.call("PUBLISH", ARGV[1] .. "SCORES_CHANNEL", "")
+return 1
 "#
         .trim()
+        .replace("SCORES_CHANNEL", SCORES_CHANNEL)
         .replace("GUESS_CHANNEL", GUESS_CHANNEL),
     )
 });
 
 static UNGUESS_NAME_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
-    Script::new(
+    room_script(
         &r#"
-server.call("SETBIT", KEYS[1], ARGV[1], 0)
-server.call("PUBLISH", "UNGUESS_CHANNEL", ARGV[1])
+server.call("SETBIT", KEYS[1], ARGV[2], 0)
+server.call("PUBLISH", ARGV[1] .. "UNGUESS_CHANNEL", ARGV[2])
+
+-- take the point back from whoever guessed it
+local previous = server.call("HGET", KEYS[2], ARGV[2])
+if previous then
+    server.call("HINCRBY", KEYS[3], previous, -1)
+    server.call("HINCRBY", KEYS[4], previous, -1)
+    server.call("HDEL", KEYS[2], ARGV[2])
+    server.call("PUBLISH", ARGV[1] .. "SCORES_CHANNEL", "")
+end
 "#
         .trim()
+        .replace("SCORES_CHANNEL", SCORES_CHANNEL)
         .replace("UNGUESS_CHANNEL", UNGUESS_CHANNEL),
     )
 });
 
 static CHANGE_STATE_TO_SUBMITTING: LazyLock<Script> = LazyLock::new(|| {
-    Script::new(
+    room_script(
         &r#"
--- clear names and guesses
+-- clear names, their duplicate keys and approval queue, authors, guesses and the round's scores
 server.call("DEL", KEYS[3])
+server.call("DEL", KEYS[9])
+server.call("DEL", KEYS[10])
 server.call("DEL", KEYS[4])
+server.call("DEL", KEYS[5])
+server.call("DEL", KEYS[6])
+server.call("DEL", KEYS[7])
 
 -- set state
 server.call("SET", KEYS[1], "SUBMITTING_STATE")
 local epoch = server.call("INCR", KEYS[2])
+if ARGV[2] == "" then
+    server.call("DEL", KEYS[8])
+else
+    server.call("SET", KEYS[8], ARGV[2])
+end
 
 -- publish state change
-server.call("PUBLISH", "STATE_SUBMITTING_CHANNEL", epoch)
+server.call("PUBLISH", ARGV[1] .. "STATE_SUBMITTING_CHANNEL", epoch)
+server.call("PUBLISH", ARGV[1] .. "SCORES_CHANNEL", "")
 "#
         .trim()
+        .replace("SCORES_CHANNEL", SCORES_CHANNEL)
         .replace("STATE_SUBMITTING_CHANNEL", STATE_SUBMITTING_CHANNEL)
         .replace("SUBMITTING_STATE", GameState::SUBMITTING),
     )
 });
 
 static CHANGE_STATE_TO_PLAYING: LazyLock<Script> = LazyLock::new(|| {
-    Script::new(
+    room_script(
         &r#"
--- shuffle names
-math.randomseed(ARGV[1])
-local names = server.call("HVALS", KEYS[2])
-for i = 1, #names - 1 do
-    local j = math.random(i, #names)
-    names[i], names[j] = names[j], names[i]
+-- shuffle names, keeping each name's author at the same index
+math.randomseed(ARGV[2])
+local ids = server.call("HKEYS", KEYS[2])
+for i = 1, #ids - 1 do
+    local j = math.random(i, #ids)
+    ids[i], ids[j] = ids[j], ids[i]
+end
+local names = {}
+local authors = {}
+local merged = {}
+-- names still waiting for approval don't make it into the game
+local pending = {}
+for _, id in ipairs(server.call("LRANGE", KEYS[5], 0, -1)) do
+    pending[id] = true
+end
+for _, id in ipairs(ids) do
+    if not pending[id] then
+        local author = server.call("HGET", KEYS[3], id) or ""
+        local key = server.call("HGET", KEYS[4], id)
+        -- when merging, append the author to the first copy of the name
+        local index = ARGV[3] == "1" and key and merged[key]
+        if index then
+            authors[index] = authors[index] .. author
+        else
+            names[#names + 1] = server.call("HGET", KEYS[2], id)
+            authors[#authors + 1] = author
+            if key then
+                merged[key] = #names
+            end
+        end
+    end
 end
 server.call("DEL", KEYS[2])
-server.call("RPUSH", KEYS[2], unpack(names))
+server.call("DEL", KEYS[3])
+server.call("DEL", KEYS[4])
+server.call("DEL", KEYS[5])
+if #names > 0 then
+    server.call("RPUSH", KEYS[2], unpack(names))
+    server.call("RPUSH", KEYS[3], unpack(authors))
+end
+if #names < #ids then
+    -- some names were merged or dropped
+    server.call("PUBLISH", ARGV[1] .. "NUM_NAMES_CHANNEL", #names)
+end
 
 -- set state
 server.call("SET", KEYS[1], "PLAYING_STATE")
 
 -- publish state change
-server.call("PUBLISH", "STATE_PLAYING_CHANNEL", "")
+server.call("PUBLISH", ARGV[1] .. "STATE_PLAYING_CHANNEL", "")
 "#
         .trim()
+        .replace("NUM_NAMES_CHANNEL", NUM_NAMES_CHANNEL)
         .replace("STATE_PLAYING_CHANNEL", STATE_PLAYING_CHANNEL)
         .replace("PLAYING_STATE", GameState::PLAYING),
     )
 });
 
-#[derive(Debug)]
+static CHANGE_STATE_TO_REVEALING: LazyLock<Script> = LazyLock::new(|| {
+    room_script(
+        &r#"
+-- set state
+server.call("SET", KEYS[1], "REVEALING_STATE")
+
+-- publish state change
+server.call("PUBLISH", ARGV[1] .. "STATE_REVEALING_CHANNEL", "")
+"#
+        .trim()
+        .replace("STATE_REVEALING_CHANNEL", STATE_REVEALING_CHANNEL)
+        .replace("REVEALING_STATE", GameState::REVEALING),
+    )
+});
+
+// the names (and authors) are a hash keyed by id while submitting and a list
+// in shuffled order afterwards, so reads that don't go by the room's state
+// check the key's type in the same script as they read it
+
+// takes the state, epoch and names keys, and returns the state, the epoch and
+// the number of names
+static READ_ROOM_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r#"
+local num_names
+if server.call("TYPE", KEYS[3]).ok == "list" then
+    num_names = server.call("LLEN", KEYS[3])
+else
+    num_names = server.call("HLEN", KEYS[3])
+end
+return {server.call("GET", KEYS[1]), server.call("GET", KEYS[2]), num_names}
+"#
+        .trim(),
+    )
+});
+
+// takes the names and guesses keys, and returns the names and the guesses
+static NAMES_AND_GUESSES_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r#"
+local names
+if server.call("TYPE", KEYS[1]).ok == "list" then
+    names = server.call("LRANGE", KEYS[1], 0, -1)
+else
+    names = server.call("HVALS", KEYS[1])
+end
+return {names, server.call("GET", KEYS[2])}
+"#
+        .trim(),
+    )
+});
+
+// takes the authors key, and returns the author of every name while
+// submitting, or nothing afterwards
+static SUBMITTING_AUTHORS_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r#"
+if server.call("TYPE", KEYS[1]).ok ~= "hash" then
+    return {}
+end
+return server.call("HVALS", KEYS[1])
+"#
+        .trim(),
+    )
+});
+
+// takes the authors and names keys and a player id as ARGV[1], and returns
+// the ids and names of the player's names while submitting, or nothing
+// afterwards
+static PLAYER_NAMES_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r#"
+if server.call("TYPE", KEYS[1]).ok ~= "hash" then
+    return {}
+end
+local authors = server.call("HGETALL", KEYS[1])
+local names = {}
+for i = 1, #authors, 2 do
+    if authors[i + 1] == ARGV[1] then
+        local name = server.call("HGET", KEYS[2], authors[i])
+        if name then
+            table.insert(names, authors[i])
+            table.insert(names, name)
+        end
+    end
+end
+return names
+"#
+        .trim(),
+    )
+});
+
+/// Key and channel names of a single room.
+#[derive(Clone, Debug)]
+struct RoomKeys {
+    channel_prefix: String,
+    names: String,
+    guesses: String,
+    state: String,
+    epoch: String,
+    theme: String,
+    used_prompts: String,
+    name_limit: String,
+    duplicate_policy: String,
+    name_keys: String,
+    pending: String,
+    authors: String,
+    players: String,
+    connections: String,
+    connection_expiry: String,
+    guessers: String,
+    round_scores: String,
+    total_scores: String,
+    host_nonce: String,
+}
+
+impl RoomKeys {
+    fn all(&self) -> [&str; 18] {
+        [
+            &self.names,
+            &self.guesses,
+            &self.state,
+            &self.epoch,
+            &self.theme,
+            &self.used_prompts,
+            &self.name_limit,
+            &self.duplicate_policy,
+            &self.name_keys,
+            &self.pending,
+            &self.authors,
+            &self.players,
+            &self.connections,
+            &self.connection_expiry,
+            &self.guessers,
+            &self.round_scores,
+            &self.total_scores,
+            &self.host_nonce,
+        ]
+    }
+
+    fn new(code: &RoomCode) -> Self {
+        let prefix = format!("{ROOM_PREFIX}:{code}:");
+        Self {
+            names: format!("{prefix}{NAMES_KEY}"),
+            guesses: format!("{prefix}{GUESSES_KEY}"),
+            state: format!("{prefix}{STATE_KEY}"),
+            epoch: format!("{prefix}{EPOCH_KEY}"),
+            theme: format!("{prefix}{THEME_KEY}"),
+            used_prompts: format!("{prefix}{USED_PROMPTS_KEY}"),
+            name_limit: format!("{prefix}{NAME_LIMIT_KEY}"),
+            duplicate_policy: format!("{prefix}{DUPLICATE_POLICY_KEY}"),
+            name_keys: format!("{prefix}{NAME_KEYS_KEY}"),
+            pending: format!("{prefix}{PENDING_KEY}"),
+            authors: format!("{prefix}{AUTHORS_KEY}"),
+            players: format!("{prefix}{PLAYERS_KEY}"),
+            connections: format!("{prefix}{CONNECTIONS_KEY}"),
+            connection_expiry: format!("{prefix}{CONNECTION_EXPIRY_KEY}"),
+            guessers: format!("{prefix}{GUESSERS_KEY}"),
+            round_scores: format!("{prefix}{ROUND_SCORES_KEY}"),
+            total_scores: format!("{prefix}{TOTAL_SCORES_KEY}"),
+            host_nonce: format!("{prefix}{HOST_NONCE_KEY}"),
+            channel_prefix: prefix,
+        }
+    }
+}
+
+fn handle_message(channels: &RoomChannels, channel: &str, payload: &Value) {
+    match channel {
+        NUM_NAMES_CHANNEL => {
+            let Ok(num_names) = payload.try_from_str::<usize>() else {
+                warn!("got non-numeric number of names on channel: {payload:?}");
+                return;
+            };
+            channels.set_name_count(num_names);
+        }
+        GUESS_CHANNEL => {
+            let Ok(index) = payload.try_from_str::<usize>() else {
+                warn!("got non-numeric guess index on channel: {payload:?}");
+                return;
+            };
+            channels.publish_guess(index);
+        }
+        UNGUESS_CHANNEL => {
+            let Ok(index) = payload.try_from_str::<usize>() else {
+                warn!("got non-numeric unguess index on channel: {payload:?}");
+                return;
+            };
+            channels.publish_unguess(index);
+        }
+        SCORES_CHANNEL => channels.publish_scores_changed(),
+        PENDING_CHANNEL => channels.publish_pending_changed(),
+        NAMES_CHANGED_CHANNEL => channels.publish_names_changed(),
+        PRESENCE_CHANNEL => channels.publish_presence_changed(),
+        NAME_LIMIT_CHANNEL => channels.publish_name_limit_changed(),
+        NAME_SUBMITTED_CHANNEL => {
+            let submission = match payload {
+                Value::BulkString(bytes) if bytes.len() > 16 => Uuid::from_slice(&bytes[..16])
+                    .ok()
+                    .zip(String::from_utf8(bytes[16..].to_vec()).ok()),
+                _ => None,
+            };
+            let Some((id, name)) = submission else {
+                warn!("got malformed name submission on channel: {payload:?}");
+                return;
+            };
+            channels.publish_submitted(name, id);
+        }
+        NAME_UNSUBMITTED_CHANNEL => {
+            let id = match payload {
+                Value::BulkString(bytes) => Uuid::from_slice(bytes).ok(),
+                _ => None,
+            };
+            let Some(id) = id else {
+                warn!("got malformed name id on unsubmitted channel: {payload:?}");
+                return;
+            };
+            channels.publish_unsubmitted(id);
+        }
+        NAME_REJECTED_CHANNEL => {
+            let rejection = match payload {
+                Value::BulkString(ids) if ids.len() == 32 => Uuid::from_slice(&ids[..16])
+                    .ok()
+                    .zip(Uuid::from_slice(&ids[16..]).ok()),
+                _ => None,
+            };
+            let Some((id, author)) = rejection else {
+                warn!("got malformed name rejection on channel: {payload:?}");
+                return;
+            };
+            channels.publish_rejection(id, PlayerId(author));
+        }
+        STATE_SUBMITTING_CHANNEL => {
+            let Ok(epoch) = payload.try_from_str::<u32>() else {
+                warn!("got non-integer on submitting state change channel: {payload:?}");
+                return;
+            };
+            channels.enter_submitting(Epoch(epoch));
+        }
+        STATE_PLAYING_CHANNEL => channels.enter_playing(),
+        STATE_REVEALING_CHANNEL => channels.enter_revealing(),
+        _ => {}
+    }
+}
+
+type RoomMap = Arc<Mutex<HashMap<RoomCode, Weak<RoomChannels>>>>;
+
+/// Keeps the subscription to every room's channels alive, subscribing again
+/// with backoff whenever it's lost. Anything published while we weren't
+/// subscribed is missed, so every open room is read again afterwards.
+async fn run_subscriber(client: Client, mut conn: ConnectionManager, rooms: RoomMap) {
+    let mut delay = RECONNECT_MIN_DELAY;
+    let mut resubscribing = false;
+    loop {
+        match subscribe(&client).await {
+            Ok((_subscription, receiver)) => {
+                if resubscribing {
+                    resync_rooms(&mut conn, &rooms).await;
+                }
+                delay = RECONNECT_MIN_DELAY;
+                let err = forward_messages(receiver, &rooms).await;
+                error!("lost subscription to room channels: {err:?}");
+                resubscribing = true;
+            }
+            Err(err) => {
+                error!("couldn't subscribe to room channels, retrying in {delay:?}: {err:?}");
+            }
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+    }
+}
+
+/// Opens a connection subscribed to every room's channels. Messages arrive on
+/// the receiver for as long as the connection is kept around.
+async fn subscribe(
+    client: &Client,
+) -> miette::Result<(MultiplexedConnection, broadcast::Receiver<redis::PushInfo>)> {
+    let (sender, receiver) = broadcast::channel(512);
+    let config = AsyncConnectionConfig::new().set_push_sender(sender);
+    let mut conn = client
+        .get_multiplexed_async_connection_with_config(&config)
+        .await
+        .into_diagnostic()
+        .wrap_err("establish subscription connection with redis")?;
+    conn.psubscribe(ROOM_CHANNEL_PATTERN)
+        .await
+        .into_diagnostic()
+        .wrap_err("subscribe to room channels")?;
+    Ok((conn, receiver))
+}
+
+/// Forwards messages to the rooms open on this server until the subscription
+/// fails.
+async fn forward_messages(
+    mut receiver: broadcast::Receiver<redis::PushInfo>,
+    rooms: &RoomMap,
+) -> miette::Report {
+    loop {
+        let push = match receiver.recv().await {
+            Ok(push) => push,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("missed {skipped} messages on room channels");
+                continue;
+            }
+            Err(RecvError::Closed) => return miette!("subscription connection closed"),
+        };
+        match push.kind {
+            PushKind::PMessage => dispatch(rooms, &push.data),
+            PushKind::Disconnection => return miette!("disconnected from redis"),
+            _ => {}
+        }
+    }
+}
+
+/// Reads the state of every room open on this server again and has their
+/// connections send fresh snapshots.
+async fn resync_rooms(conn: &mut ConnectionManager, rooms: &RoomMap) {
+    let open: Vec<_> = rooms
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|(code, channels)| Some((code.clone(), channels.upgrade()?)))
+        .collect();
+    for (code, channels) in open {
+        match read_room(conn, &RoomKeys::new(&code)).await {
+            Ok(Some((game_state, num_names))) => channels.resync(num_names, game_state),
+            Ok(None) => warn!("room {code} disappeared while we were disconnected"),
+            Err(err) => error!("couldn't resync room {code}: {err:?}"),
+        }
+    }
+}
+
+fn dispatch(rooms: &RoomMap, data: &[Value]) {
+    // pattern messages carry [pattern, channel, payload]
+    let [_, channel, payload] = data else {
+        return;
+    };
+    let Ok(channel) = channel.try_as_str() else {
+        return;
+    };
+    let Some((code, channel)) = channel
+        .strip_prefix(ROOM_PREFIX)
+        .and_then(|rest| rest.strip_prefix(':'))
+        .and_then(|rest| rest.split_once(':'))
+    else {
+        return;
+    };
+    let Ok(code) = RoomCode::try_from(code.to_string()) else {
+        warn!("got message for invalid room code on channel: {code:?}");
+        return;
+    };
+
+    let channels = {
+        let mut rooms = rooms.lock().unwrap();
+        match rooms.get(&code).map(Weak::upgrade) {
+            Some(Some(channels)) => channels,
+            Some(None) => {
+                rooms.remove(&code);
+                return;
+            }
+            None => return,
+        }
+    };
+    handle_message(&channels, channel, payload);
+}
+
 pub struct RedisWrapper {
     _client: Client,
-    conn: MultiplexedConnection,
-    num_names_receiver: WatchReceiver<usize>,
-    guess_receiver: BroadcastReceiver<usize>,
-    unguess_receiver: BroadcastReceiver<usize>,
-    state_change_receiver: WatchReceiver<GameState>,
+    conn: ConnectionManager,
+    rooms: RoomMap,
+    /// How long rooms are kept after they were last touched.
+    room_ttl: Duration,
 }
 
 impl RedisWrapper {
-    pub async fn new(url: SecretString) -> miette::Result<Self> {
+    pub async fn new(url: SecretString, room_ttl: Duration) -> miette::Result<Self> {
         let client = Client::open(url.expose_secret())
             .into_diagnostic()
             .wrap_err("create redis client")?;
 
-        let (sender, mut receiver) = tokio::sync::broadcast::channel::<redis::PushInfo>(512);
-        let config = AsyncConnectionConfig::new().set_push_sender(sender);
-        let mut conn = client
-            .get_multiplexed_async_connection_with_config(&config)
+        let config = ConnectionManagerConfig::new()
+            .set_factor(RECONNECT_MIN_DELAY.as_millis() as u64)
+            .set_max_delay(RECONNECT_MAX_DELAY.as_millis() as u64);
+        let conn = ConnectionManager::new_with_config(client.clone(), config)
             .await
             .into_diagnostic()
             .wrap_err("establish connection with redis")?;
-        conn.subscribe(&[
-            NUM_NAMES_CHANNEL,
-            GUESS_CHANNEL,
-            UNGUESS_CHANNEL,
-            STATE_SUBMITTING_CHANNEL,
-            STATE_PLAYING_CHANNEL,
-        ])
-        .await
-        .into_diagnostic()?;
 
-        let num_names = conn
-            .llen(NAMES_KEY)
-            .await
-            .into_diagnostic()
-            .wrap_err("get initial name count")?;
-        let (num_names_sender, num_names_receiver) = tokio::sync::watch::channel(num_names);
+        let rooms = RoomMap::default();
+        tokio::spawn(run_subscriber(client.clone(), conn.clone(), rooms.clone()));
 
-        let (guess_sender, guess_receiver) = tokio::sync::broadcast::channel(128);
-        let (unguess_sender, unguess_receiver) = tokio::sync::broadcast::channel(128);
+        Ok(Self {
+            _client: client,
+            conn,
+            rooms,
+            room_ttl,
+        })
+    }
+}
 
-        let game_state = match redis::pipe()
-            .get(STATE_KEY)
-            .get(EPOCH_KEY)
-            .query_async::<(Option<String>, Option<u32>)>(&mut conn)
+impl RedisWrapper {
+    /// Key for signing tokens, shared by every server using the same Valkey
+    /// instance. Whichever server asks first generates it.
+    pub async fn token_secret(&self) -> miette::Result<SecretString> {
+        let mut key = [0u8; 32];
+        rng().fill(&mut key);
+        let (secret,): (String,) = redis::pipe()
+            .atomic()
+            .cmd("SET")
+            .arg(TOKEN_SECRET_KEY)
+            .arg(URL_SAFE_NO_PAD.encode(key))
+            .arg("NX")
+            .ignore()
+            .get(TOKEN_SECRET_KEY)
+            .query_async(&mut self.conn.clone())
             .await
-        {
-            Ok((Some(state), epoch)) if state == GameState::SUBMITTING => {
-                GameState::Submitting(Epoch(epoch.unwrap_or(0)))
-            }
-            Ok((Some(state), _)) if state == GameState::PLAYING => GameState::Playing,
-            Ok((Some(state), _)) => {
-                bail!("unknown state while getting initial game state: {state}")
+            .into_diagnostic()
+            .wrap_err("get token secret")?;
+        Ok(SecretString::from(secret))
+    }
+}
+
+#[async_trait]
+impl RoomStore for RedisWrapper {
+    async fn create_room(&self) -> miette::Result<Arc<dyn GameStore>> {
+        let mut conn = self.conn.clone();
+        for _ in 0..CREATE_ROOM_ATTEMPTS {
+            let code = RoomCode::generate();
+            let keys = RoomKeys::new(&code);
+            let mut invocation = CREATE_ROOM_SCRIPT.prepare_invoke();
+            invocation.key(&keys.state).key(&keys.host_nonce);
+            for key in keys.all() {
+                invocation.key(key);
             }
-            Ok((None, epoch)) => GameState::Submitting(Epoch(epoch.unwrap_or(0))),
-            Err(err) => {
-                return Err(err)
-                    .into_diagnostic()
-                    .wrap_err("get initial game state");
+            let created: bool = invocation
+                .arg(self.room_ttl.as_secs())
+                .arg(Uuid::new_v4())
+                .invoke_async(&mut conn)
+                .await
+                .into_diagnostic()
+                .wrap_err("create room")?;
+            if created {
+                return self
+                    .room(code)
+                    .await?
+                    .ok_or_else(|| miette::miette!("room vanished right after creation"));
             }
-        };
-        let (state_change_sender, state_change_receiver) = tokio::sync::watch::channel(game_state);
+        }
+        bail!("no free room code after {CREATE_ROOM_ATTEMPTS} attempts")
+    }
 
-        tokio::spawn(async move {
-            loop {
-                let push = receiver.recv().await.unwrap();
-                let PushKind::Message = push.kind else {
-                    continue;
-                };
-                let Ok(channel) = push.data[0].try_as_str() else {
-                    continue;
+    async fn room(&self, code: RoomCode) -> miette::Result<Option<Arc<dyn GameStore>>> {
+        let keys = RoomKeys::new(&code);
+
+        let existing = self
+            .rooms
+            .lock()
+            .unwrap()
+            .get(&code)
+            .and_then(Weak::upgrade);
+        let channels = match existing {
+            Some(channels) => channels,
+            None => {
+                let Some((game_state, num_names)) =
+                    read_room(&mut self.conn.clone(), &keys).await?
+                else {
+                    return Ok(None);
                 };
-                match channel {
-                    NUM_NAMES_CHANNEL => {
-                        let Ok(num_names) = push.data[1].try_from_str::<usize>() else {
-                            warn!(
-                                "got non-numeric number of names on channel: {:?}",
-                                push.data[1]
-                            );
-                            continue;
-                        };
-                        num_names_sender.send_replace(num_names);
-                    }
-                    GUESS_CHANNEL => {
-                        let Ok(index) = push.data[1].try_from_str::<usize>() else {
-                            warn!("got non-numeric guess index on channel: {:?}", push.data[1]);
-                            continue;
-                        };
-                        guess_sender.send(index).expect(
-                            "there should be at least one receiver listening to the guess channel",
-                        );
-                    }
-                    UNGUESS_CHANNEL => {
-                        let Ok(index) = push.data[1].try_from_str::<usize>() else {
-                            warn!(
-                                "got non-numeric unguess index on channel: {:?}",
-                                push.data[1]
-                            );
-                            continue;
-                        };
-                        unguess_sender.send(index).expect(
-                            "there should be at least one receiver listening to the unguess channel",
-                        );
-                    }
-                    STATE_SUBMITTING_CHANNEL => {
-                        let Ok(epoch) = push.data[1].try_from_str::<u32>() else {
-                            warn!(
-                                "got non-integer on submitting state change channel: {:?}",
-                                push.data[1]
-                            );
-                            continue;
-                        };
-                        // update number of names without sending a
-                        // notification (no notification is needed, as any
-                        // currently connected displays will get a 0 num names
-                        // packet when the state change is observed)
-                        num_names_sender.send_if_modified(|num| {
-                            *num = 0;
-                            false
-                        });
-                        state_change_sender.send_replace(GameState::Submitting(Epoch(epoch)));
-                    }
-                    STATE_PLAYING_CHANNEL => {
-                        state_change_sender.send_replace(GameState::Playing);
+
+                // another connection may have set the room up while we were
+                // reading its state
+                let mut rooms = self.rooms.lock().unwrap();
+                match rooms.get(&code).and_then(Weak::upgrade) {
+                    Some(channels) => channels,
+                    None => {
+                        let channels = Arc::new(RoomChannels::new(num_names, game_state));
+                        rooms.insert(code.clone(), Arc::downgrade(&channels));
+                        channels
                     }
-                    _ => {}
                 }
             }
-        });
+        };
 
-        Ok(Self {
-            _client: client,
-            conn,
-            num_names_receiver,
-            guess_receiver,
-            unguess_receiver,
-            state_change_receiver,
-        })
+        Ok(Some(Arc::new(RedisRoom {
+            code,
+            keys,
+            conn: self.conn.clone(),
+            channels,
+            ttl: self.room_ttl,
+            rooms: self.rooms.clone(),
+        })))
     }
+}
+
+/// Reads a room's game state and name count, or `None` if there's no such
+/// room.
+async fn read_room(
+    conn: &mut ConnectionManager,
+    keys: &RoomKeys,
+) -> miette::Result<Option<(GameState, usize)>> {
+    let (state, epoch, num_names): (Option<String>, Option<u32>, usize) = READ_ROOM_SCRIPT
+        .key(&keys.state)
+        .key(&keys.epoch)
+        .key(&keys.names)
+        .invoke_async(conn)
+        .await
+        .into_diagnostic()
+        .wrap_err("read room")?;
+    let game_state = match state {
+        Some(state) if state == GameState::SUBMITTING => {
+            GameState::Submitting(Epoch(epoch.unwrap_or(0)))
+        }
+        Some(state) if state == GameState::PLAYING => GameState::Playing,
+        Some(state) if state == GameState::REVEALING => GameState::Revealing,
+        Some(state) => bail!("unknown state while getting game state: {state}"),
+        None => return Ok(None),
+    };
+    Ok(Some((game_state, num_names)))
+}
+
+struct RedisRoom {
+    code: RoomCode,
+    keys: RoomKeys,
+    conn: ConnectionManager,
+    channels: Arc<RoomChannels>,
+    ttl: Duration,
+    rooms: RoomMap,
+}
 
-    pub fn name_count(&self) -> usize {
-        *self.num_names_receiver.borrow()
+impl Drop for RedisRoom {
+    fn drop(&mut self) {
+        // forget the room once its last handle goes, rather than waiting for
+        // a message to it that may never come
+        let mut rooms = self.rooms.lock().unwrap();
+        if Arc::strong_count(&self.channels) == 1
+            && rooms
+                .get(&self.code)
+                .is_some_and(|entry| entry.ptr_eq(&Arc::downgrade(&self.channels)))
+        {
+            rooms.remove(&self.code);
+        }
     }
+}
 
-    pub fn name_count_stream(&self) -> impl Stream<Item = usize> {
-        let mut receiver = self.num_names_receiver.clone();
-        receiver.mark_unchanged();
-        WatchStream::from_changes(receiver)
+#[async_trait]
+impl GameStore for RedisRoom {
+    fn code(&self) -> &RoomCode {
+        &self.code
     }
 
-    pub async fn add_name(&self, name: &str) -> miette::Result<Uuid> {
-        ADD_NAME_SCRIPT
-            .key(NAMES_KEY)
+    fn channels(&self) -> &RoomChannels {
+        &self.channels
+    }
+
+    async fn host_nonce(&self) -> miette::Result<Uuid> {
+        let nonce: Option<Uuid> = redis::cmd("GET")
+            .arg(&self.keys.host_nonce)
+            .query_async(&mut self.conn.clone())
+            .await
+            .into_diagnostic()
+            .wrap_err("get host nonce")?;
+        // rooms from before host nonces were added have none, but their codes
+        // can't have been reused yet
+        Ok(nonce.unwrap_or_default())
+    }
+
+    async fn touch(&self) -> miette::Result<()> {
+        let mut invocation = TOUCH_ROOM_SCRIPT.prepare_invoke();
+        for key in self.keys.all() {
+            invocation.key(key);
+        }
+        invocation
+            .arg(self.ttl.as_secs())
+            .invoke_async(&mut self.conn.clone())
+            .await
+            .into_diagnostic()
+            .wrap_err("touch room")
+    }
+
+    async fn set_nickname(&self, player: &Player) -> miette::Result<()> {
+        redis::pipe()
+            .atomic()
+            .hset(&self.keys.players, player.id.0, &player.nickname)
+            .expire(&self.keys.players, self.ttl.as_secs() as i64)
+            .publish(format!("{}{SCORES_CHANNEL}", self.keys.channel_prefix), "")
+            .exec_async(&mut self.conn.clone())
+            .await
+            .into_diagnostic()
+            .wrap_err("set nickname")
+    }
+
+    async fn nickname(&self, player: &PlayerId) -> miette::Result<Option<String>> {
+        self.conn
+            .clone()
+            .hget(&self.keys.players, player.0)
+            .await
+            .into_diagnostic()
+            .wrap_err("get nickname")
+    }
+
+    async fn add_connection(
+        &self,
+        id: &Uuid,
+        player: &PlayerId,
+        ttl: Duration,
+    ) -> miette::Result<()> {
+        ADD_CONNECTION_SCRIPT
+            .key(&self.keys.connections)
+            .key(&self.keys.connection_expiry)
+            .arg(&self.keys.channel_prefix)
+            .arg(id)
+            .arg(player.0)
+            .arg(ttl.as_millis() as u64)
+            .arg(self.ttl.as_secs())
+            .invoke_async(&mut self.conn.clone())
+            .await
+            .into_diagnostic()
+            .wrap_err("add connection")
+    }
+
+    async fn remove_connection(&self, id: &Uuid) -> miette::Result<()> {
+        REMOVE_CONNECTION_SCRIPT
+            .key(&self.keys.connections)
+            .key(&self.keys.connection_expiry)
+            .arg(&self.keys.channel_prefix)
+            .arg(id)
+            .arg(self.ttl.as_secs())
+            .invoke_async(&mut self.conn.clone())
+            .await
+            .into_diagnostic()
+            .wrap_err("remove connection")
+    }
+
+    async fn roster(&self) -> miette::Result<Vec<RosterEntry>> {
+        let players: Vec<Uuid> = EXPIRE_CONNECTIONS_SCRIPT
+            .key(&self.keys.connections)
+            .key(&self.keys.connection_expiry)
+            .arg(&self.keys.channel_prefix)
+            .arg(self.ttl.as_secs())
+            .invoke_async(&mut self.conn.clone())
+            .await
+            .into_diagnostic()
+            .wrap_err("expire connections")?;
+        let players: Vec<Uuid> = players
+            .into_iter()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        if players.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let nicknames: Vec<Option<String>> = redis::cmd("HMGET")
+            .arg(&self.keys.players)
+            .arg(&players)
+            .query_async(&mut self.conn.clone())
+            .await
+            .into_diagnostic()
+            .wrap_err("get nicknames")?;
+        // names only have authors by id until the game starts
+        let authors: Vec<Uuid> = SUBMITTING_AUTHORS_SCRIPT
+            .key(&self.keys.authors)
+            .invoke_async(&mut self.conn.clone())
+            .await
+            .into_diagnostic()
+            .wrap_err("get authors")?;
+        let mut names = HashMap::<Uuid, u32>::new();
+        for author in authors {
+            *names.entry(author).or_default() += 1;
+        }
+
+        let mut roster: Vec<_> = players
+            .into_iter()
+            .zip(nicknames)
+            .map(|(player, nickname)| RosterEntry {
+                player: PlayerId(player),
+                nickname: nickname.unwrap_or_default(),
+                names: names.get(&player).copied().unwrap_or_default(),
+            })
+            .collect();
+        roster.sort_by(|a, b| {
+            a.nickname
+                .cmp(&b.nickname)
+                .then(a.player.0.cmp(&b.player.0))
+        });
+        Ok(roster)
+    }
+
+    async fn add_name(
+        &self,
+        name: &str,
+        author: &PlayerId,
+        limit: Option<u32>,
+        reject_duplicates: bool,
+        hold: bool,
+    ) -> miette::Result<AddedName> {
+        let id = Uuid::new_v4();
+        let status: u8 = ADD_NAME_SCRIPT
+            .key(&self.keys.names)
+            .key(&self.keys.authors)
+            .key(&self.keys.name_keys)
+            .key(&self.keys.pending)
+            .arg(&self.keys.channel_prefix)
             .arg(name)
-            .arg(Uuid::new_v4())
+            .arg(id)
+            .arg(author.0)
+            .arg(limit.map(|limit| limit.to_string()).unwrap_or_default())
+            .arg(dedup_key(name))
+            .arg(if reject_duplicates { "1" } else { "0" })
+            .arg(if hold { "1" } else { "0" })
+            .arg(self.ttl.as_secs())
             .invoke_async(&mut self.conn.clone())
             .await
             .into_diagnostic()
-            .wrap_err("add name")
+            .wrap_err("add name")?;
+        match status {
+            0 => Ok(AddedName::Added(id)),
+            1 => Ok(AddedName::LimitReached),
+            2 => Ok(AddedName::Duplicate),
+            _ => bail!("unexpected result from add name script: {status}"),
+        }
     }
 
-    pub async fn remove_name(&self, id: &Uuid) -> miette::Result<()> {
-        REMOVE_NAME_SCRIPT
-            .key(NAMES_KEY)
+    async fn remove_name(&self, id: &Uuid, author: &PlayerId) -> miette::Result<RemovedName> {
+        let status: u8 = REMOVE_NAME_SCRIPT
+            .key(&self.keys.names)
+            .key(&self.keys.authors)
+            .key(&self.keys.name_keys)
+            .key(&self.keys.pending)
+            .arg(&self.keys.channel_prefix)
             .arg(id)
+            .arg(author.0)
+            .arg(self.ttl.as_secs())
             .invoke_async(&mut self.conn.clone())
             .await
             .into_diagnostic()
-            .wrap_err("remove name")
+            .wrap_err("remove name")?;
+        match status {
+            0 => Ok(RemovedName::NotAuthor),
+            1 => Ok(RemovedName::Removed),
+            2 => Ok(RemovedName::NoSuchName),
+            _ => bail!("unexpected result from remove name script: {status}"),
+        }
     }
 
-    pub async fn names_and_guesses(&self) -> miette::Result<(Vec<String>, Vec<u8>)> {
-        let mut pipe = redis::pipe();
-        match self.state() {
-            GameState::Submitting(_) => pipe.hvals(NAMES_KEY),
-            GameState::Playing => pipe.lrange(NAMES_KEY, 0, -1),
-        };
-        let (names, mut guesses): (Vec<String>, Vec<u8>) = pipe
-            .get(GUESSES_KEY)
+    async fn remove_name_as_host(&self, id: &Uuid) -> miette::Result<bool> {
+        let status: u8 = REMOVE_NAME_SCRIPT
+            .key(&self.keys.names)
+            .key(&self.keys.authors)
+            .key(&self.keys.name_keys)
+            .key(&self.keys.pending)
+            .arg(&self.keys.channel_prefix)
+            .arg(id)
+            .arg("")
+            .arg(self.ttl.as_secs())
+            .invoke_async(&mut self.conn.clone())
+            .await
+            .into_diagnostic()
+            .wrap_err("remove name as host")?;
+        Ok(status == 1)
+    }
+
+    async fn remove_name_at(&self, index: usize) -> miette::Result<bool> {
+        REMOVE_NAME_AT_SCRIPT
+            .key(&self.keys.names)
+            .key(&self.keys.authors)
+            .key(&self.keys.guesses)
+            .key(&self.keys.guessers)
+            .key(&self.keys.round_scores)
+            .key(&self.keys.total_scores)
+            .arg(&self.keys.channel_prefix)
+            .arg(index)
+            .arg(Uuid::new_v4().to_string())
+            .arg(self.ttl.as_secs())
+            .invoke_async(&mut self.conn.clone())
+            .await
+            .into_diagnostic()
+            .wrap_err("remove name at index")
+    }
+
+    async fn submitted_names(&self) -> miette::Result<Vec<(String, Uuid)>> {
+        let (names, pending): (HashMap<Uuid, String>, Vec<Uuid>) = redis::pipe()
+            .hgetall(&self.keys.names)
+            .lrange(&self.keys.pending, 0, -1)
             .query_async(&mut self.conn.clone())
             .await
             .into_diagnostic()
+            .wrap_err("get submitted names")?;
+        Ok(names
+            .into_iter()
+            .filter(|(id, _)| !pending.contains(id))
+            .map(|(id, name)| (name, id))
+            .collect())
+    }
+
+    async fn pending_names(&self) -> miette::Result<Vec<(String, Uuid)>> {
+        let mut conn = self.conn.clone();
+        let ids: Vec<Uuid> = redis::cmd("LRANGE")
+            .arg(&self.keys.pending)
+            .arg(0)
+            .arg(-1)
+            .query_async(&mut conn)
+            .await
+            .into_diagnostic()
+            .wrap_err("get pending names")?;
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let names: Vec<Option<String>> = redis::cmd("HMGET")
+            .arg(&self.keys.names)
+            .arg(&ids)
+            .query_async(&mut conn)
+            .await
+            .into_diagnostic()
+            .wrap_err("get pending names")?;
+        Ok(names
+            .into_iter()
+            .zip(ids)
+            .filter_map(|(name, id)| Some((name?, id)))
+            .collect())
+    }
+
+    async fn approve_name(&self, id: &Uuid) -> miette::Result<bool> {
+        APPROVE_NAME_SCRIPT
+            .key(&self.keys.pending)
+            .key(&self.keys.names)
+            .arg(&self.keys.channel_prefix)
+            .arg(id)
+            .arg(self.ttl.as_secs())
+            .invoke_async(&mut self.conn.clone())
+            .await
+            .into_diagnostic()
+            .wrap_err("approve name")
+    }
+
+    async fn reject_name(&self, id: &Uuid) -> miette::Result<bool> {
+        REJECT_NAME_SCRIPT
+            .key(&self.keys.pending)
+            .key(&self.keys.names)
+            .key(&self.keys.authors)
+            .key(&self.keys.name_keys)
+            .arg(&self.keys.channel_prefix)
+            .arg(id)
+            .arg(self.ttl.as_secs())
+            .invoke_async(&mut self.conn.clone())
+            .await
+            .into_diagnostic()
+            .wrap_err("reject name")
+    }
+
+    async fn player_names(&self, player: &PlayerId) -> miette::Result<Vec<(String, Uuid)>> {
+        let names: HashMap<Uuid, String> = PLAYER_NAMES_SCRIPT
+            .key(&self.keys.authors)
+            .key(&self.keys.names)
+            .arg(player.0)
+            .invoke_async(&mut self.conn.clone())
+            .await
+            .into_diagnostic()
+            .wrap_err("get player's names")?;
+        Ok(names.into_iter().map(|(id, name)| (name, id)).collect())
+    }
+
+    async fn names_and_guesses(&self) -> miette::Result<(Vec<String>, Vec<u8>)> {
+        let (names, mut guesses): (Vec<String>, Vec<u8>) = NAMES_AND_GUESSES_SCRIPT
+            .key(&self.keys.names)
+            .key(&self.keys.guesses)
+            .invoke_async(&mut self.conn.clone())
+            .await
+            .into_diagnostic()
             .wrap_err("get names and guesses")?;
 
         // if there are more guesses than names, just get rid of the extra ones
@@ -316,69 +1389,233 @@ impl RedisWrapper {
         Ok((names, guesses))
     }
 
-    pub async fn guess_name(&self, index: usize) -> miette::Result<()> {
-        GUESS_NAME_SCRIPT
-            .key(GUESSES_KEY)
+    async fn authors(&self) -> miette::Result<Vec<String>> {
+        let mut conn = self.conn.clone();
+        // each entry holds one or more (when merged) 16-byte player ids
+        let entries: Vec<Vec<u8>> = redis::cmd("LRANGE")
+            .arg(&self.keys.authors)
+            .arg(0)
+            .arg(-1)
+            .query_async(&mut conn)
+            .await
+            .into_diagnostic()
+            .wrap_err("get authors")?;
+        if entries.is_empty() {
+            return Ok(Vec::new());
+        }
+        let nicknames: HashMap<Vec<u8>, String> = redis::cmd("HGETALL")
+            .arg(&self.keys.players)
+            .query_async(&mut conn)
+            .await
+            .into_diagnostic()
+            .wrap_err("get author nicknames")?;
+        Ok(entries
+            .iter()
+            .map(|entry| {
+                entry
+                    .chunks(16)
+                    .map(|id| nicknames.get(id).cloned().unwrap_or_default())
+                    .collect::<Vec<_>>()
+                    .join(" & ")
+            })
+            .collect())
+    }
+
+    async fn guess_name(
+        &self,
+        index: usize,
+        guesser: Option<&PlayerId>,
+        reassign: bool,
+    ) -> miette::Result<GuessedName> {
+        let status: u8 = GUESS_NAME_SCRIPT
+            .key(&self.keys.guesses)
+            .key(&self.keys.guessers)
+            .key(&self.keys.authors)
+            .key(&self.keys.round_scores)
+            .key(&self.keys.total_scores)
+            .arg(&self.keys.channel_prefix)
             .arg(index)
+            .arg(guesser.map_or(&[][..], |guesser| guesser.0.as_bytes()))
+            .arg(if reassign { "1" } else { "0" })
+            .arg(self.ttl.as_secs())
             .invoke_async(&mut self.conn.clone())
             .await
             .into_diagnostic()
-            .wrap_err("guess name")
+            .wrap_err("guess name")?;
+        match status {
+            0 => Ok(GuessedName::OwnName),
+            1 => Ok(GuessedName::Guessed),
+            2 => Ok(GuessedName::AlreadyGuessed),
+            _ => bail!("unexpected result from guess name script: {status}"),
+        }
     }
 
-    pub async fn unguess_name(&self, index: usize) -> miette::Result<()> {
+    async fn unguess_name(&self, index: usize) -> miette::Result<()> {
         UNGUESS_NAME_SCRIPT
-            .key(GUESSES_KEY)
+            .key(&self.keys.guesses)
+            .key(&self.keys.guessers)
+            .key(&self.keys.round_scores)
+            .key(&self.keys.total_scores)
+            .arg(&self.keys.channel_prefix)
             .arg(index)
+            .arg(self.ttl.as_secs())
             .invoke_async(&mut self.conn.clone())
             .await
             .into_diagnostic()
             .wrap_err("unguess name")
     }
 
-    pub fn guess_stream(&self) -> impl Stream<Item = usize> {
-        BroadcastStream::new(self.guess_receiver.resubscribe())
-            .map(|res| res.expect("the guess channel's sender should not have been dropped"))
+    async fn scoreboard(&self) -> miette::Result<Vec<Score>> {
+        let (players, round_scores, total_scores): (
+            HashMap<Vec<u8>, String>,
+            HashMap<Vec<u8>, u32>,
+            HashMap<Vec<u8>, u32>,
+        ) = redis::pipe()
+            .hgetall(&self.keys.players)
+            .hgetall(&self.keys.round_scores)
+            .hgetall(&self.keys.total_scores)
+            .query_async(&mut self.conn.clone())
+            .await
+            .into_diagnostic()
+            .wrap_err("get scoreboard")?;
+
+        let mut scores: Vec<_> = players
+            .into_iter()
+            .filter_map(|(id, nickname)| {
+                let Ok(player) = Uuid::from_slice(&id) else {
+                    warn!("got invalid player id in players hash: {id:?}");
+                    return None;
+                };
+                Some(Score {
+                    player: PlayerId(player),
+                    nickname,
+                    round_points: round_scores.get(&id).copied().unwrap_or(0),
+                    total_points: total_scores.get(&id).copied().unwrap_or(0),
+                })
+            })
+            .collect();
+        Score::sort(&mut scores);
+        Ok(scores)
+    }
+
+    async fn theme(&self) -> miette::Result<Option<String>> {
+        self.conn
+            .clone()
+            .get(&self.keys.theme)
+            .await
+            .into_diagnostic()
+            .wrap_err("get theme")
+    }
+
+    async fn name_limit(&self) -> miette::Result<Option<u32>> {
+        redis::cmd("GET")
+            .arg(&self.keys.name_limit)
+            .query_async(&mut self.conn.clone())
+            .await
+            .into_diagnostic()
+            .wrap_err("get name limit")
+    }
+
+    async fn set_name_limit(&self, limit: u32) -> miette::Result<()> {
+        redis::pipe()
+            .atomic()
+            .set_ex(&self.keys.name_limit, limit, self.ttl.as_secs())
+            .publish(
+                format!("{}{NAME_LIMIT_CHANNEL}", self.keys.channel_prefix),
+                "",
+            )
+            .exec_async(&mut self.conn.clone())
+            .await
+            .into_diagnostic()
+            .wrap_err("set name limit")
     }
 
-    pub fn unguess_stream(&self) -> impl Stream<Item = usize> {
-        BroadcastStream::new(self.unguess_receiver.resubscribe())
-            .map(|res| res.expect("the unguess channel's sender should not have been dropped"))
+    async fn duplicate_policy(&self) -> miette::Result<Option<DuplicatePolicy>> {
+        let policy: Option<String> = self
+            .conn
+            .clone()
+            .get(&self.keys.duplicate_policy)
+            .await
+            .into_diagnostic()
+            .wrap_err("get duplicate policy")?;
+        Ok(policy.and_then(|policy| DuplicatePolicy::parse(&policy)))
     }
 
-    pub fn state(&self) -> GameState {
-        *self.state_change_receiver.borrow()
+    async fn set_duplicate_policy(&self, policy: DuplicatePolicy) -> miette::Result<()> {
+        self.conn
+            .clone()
+            .set_ex(
+                &self.keys.duplicate_policy,
+                policy.as_str(),
+                self.ttl.as_secs(),
+            )
+            .await
+            .into_diagnostic()
+            .wrap_err("set duplicate policy")
     }
 
-    pub fn state_change_stream(&self) -> impl Stream<Item = GameState> {
-        let mut receiver = self.state_change_receiver.clone();
-        receiver.mark_unchanged();
-        WatchStream::from_changes(receiver)
+    async fn claim_prompt(&self, prompt: &str) -> miette::Result<bool> {
+        let (added,): (u32,) = redis::pipe()
+            .atomic()
+            .sadd(&self.keys.used_prompts, prompt)
+            .expire(&self.keys.used_prompts, self.ttl.as_secs() as i64)
+            .ignore()
+            .query_async(&mut self.conn.clone())
+            .await
+            .into_diagnostic()
+            .wrap_err("claim prompt")?;
+        Ok(added == 1)
     }
 
-    pub async fn change_state_to_submitting(&self) -> miette::Result<()> {
+    async fn change_state_to_submitting(&self, theme: Option<&str>) -> miette::Result<()> {
         CHANGE_STATE_TO_SUBMITTING
-            .key(STATE_KEY)
-            .key(EPOCH_KEY)
-            .key(NAMES_KEY)
-            .key(GUESSES_KEY)
+            .key(&self.keys.state)
+            .key(&self.keys.epoch)
+            .key(&self.keys.names)
+            .key(&self.keys.authors)
+            .key(&self.keys.guesses)
+            .key(&self.keys.guessers)
+            .key(&self.keys.round_scores)
+            .key(&self.keys.theme)
+            .key(&self.keys.name_keys)
+            .key(&self.keys.pending)
+            .arg(&self.keys.channel_prefix)
+            .arg(theme.unwrap_or_default())
+            .arg(self.ttl.as_secs())
             .invoke_async(&mut self.conn.clone())
             .await
             .into_diagnostic()
             .wrap_err("set state to submitting")
     }
 
-    pub async fn change_state_to_playing(&self) -> miette::Result<()> {
+    async fn change_state_to_playing(&self, merge_duplicates: bool) -> miette::Result<()> {
         let seed = rng().random::<u32>();
         CHANGE_STATE_TO_PLAYING
-            .key(STATE_KEY)
-            .key(NAMES_KEY)
+            .key(&self.keys.state)
+            .key(&self.keys.names)
+            .key(&self.keys.authors)
+            .key(&self.keys.name_keys)
+            .key(&self.keys.pending)
+            .arg(&self.keys.channel_prefix)
             .arg(seed)
+            .arg(if merge_duplicates { "1" } else { "0" })
+            .arg(self.ttl.as_secs())
             .invoke_async(&mut self.conn.clone())
             .await
             .into_diagnostic()
             .wrap_err("set state to playing")
     }
+
+    async fn change_state_to_revealing(&self) -> miette::Result<()> {
+        CHANGE_STATE_TO_REVEALING
+            .key(&self.keys.state)
+            .arg(&self.keys.channel_prefix)
+            .arg(self.ttl.as_secs())
+            .invoke_async(&mut self.conn.clone())
+            .await
+            .into_diagnostic()
+            .wrap_err("set state to revealing")
+    }
 }
 
 trait ValueExt {
@@ -411,3 +1648,207 @@ impl ValueExt for Value {
             .wrap_err("parse value from string")
     }
 }
+
+/// These run the scripts against a real server, and are skipped unless
+/// `TEST_REDIS_URL` points at one that may be written to.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::TokenSigner;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    async fn store() -> Option<RedisWrapper> {
+        let Ok(url) = std::env::var("TEST_REDIS_URL") else {
+            eprintln!("TEST_REDIS_URL isn't set, skipping");
+            return None;
+        };
+        Some(
+            RedisWrapper::new(SecretString::from(url), TTL)
+                .await
+                .unwrap(),
+        )
+    }
+
+    async fn join(room: &dyn GameStore, nickname: &str) -> PlayerId {
+        let player = Player::new(PlayerId::new(), Some(nickname.to_string()));
+        room.set_nickname(&player).await.unwrap();
+        player.id
+    }
+
+    async fn add(room: &dyn GameStore, name: &str, author: &PlayerId) -> Uuid {
+        match room
+            .add_name(name, author, None, false, false)
+            .await
+            .unwrap()
+        {
+            AddedName::Added(id) => id,
+            added => panic!("couldn't add {name}: {added:?}"),
+        }
+    }
+
+    /// Seconds until `key` expires, -1 if it never does, or -2 if it's gone.
+    async fn ttl(store: &RedisWrapper, key: &str) -> i64 {
+        redis::cmd("TTL")
+            .arg(key)
+            .query_async(&mut store.conn.clone())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn names_are_read_in_every_state() {
+        let Some(store) = store().await else {
+            return;
+        };
+        let room = store.create_room().await.unwrap();
+        let ann = join(room.as_ref(), "Ann").await;
+        let bob = join(room.as_ref(), "Bob").await;
+        let ada = add(room.as_ref(), "Ada", &ann).await;
+        add(room.as_ref(), "Bea", &bob).await;
+        assert_eq!(
+            room.add_name("Alan", &ann, Some(1), false, false)
+                .await
+                .unwrap(),
+            AddedName::LimitReached
+        );
+        assert_eq!(
+            room.remove_name(&ada, &bob).await.unwrap(),
+            RemovedName::NotAuthor
+        );
+        assert_eq!(
+            room.player_names(&ann).await.unwrap(),
+            [("Ada".to_string(), ada)]
+        );
+        let mut names = room.names_and_guesses().await.unwrap().0;
+        names.sort();
+        assert_eq!(names, ["Ada", "Bea"]);
+
+        room.add_connection(&Uuid::new_v4(), &ann, TTL)
+            .await
+            .unwrap();
+        assert_eq!(room.roster().await.unwrap()[0].names, 1);
+
+        room.change_state_to_playing(false).await.unwrap();
+        // the names and authors are lists now, whatever this server thinks
+        // the state is
+        let (names, _) = room.names_and_guesses().await.unwrap();
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(sorted, ["Ada", "Bea"]);
+        assert!(room.player_names(&ann).await.unwrap().is_empty());
+        assert_eq!(room.roster().await.unwrap().len(), 1);
+
+        let index = names.iter().position(|name| name == "Ada").unwrap();
+        assert_eq!(
+            room.guess_name(index, Some(&ann), false).await.unwrap(),
+            GuessedName::OwnName
+        );
+        assert_eq!(
+            room.guess_name(index, Some(&bob), false).await.unwrap(),
+            GuessedName::Guessed
+        );
+        assert_eq!(
+            room.guess_name(index, Some(&bob), false).await.unwrap(),
+            GuessedName::AlreadyGuessed
+        );
+        let scores = room.scoreboard().await.unwrap();
+        let bob_score = scores.iter().find(|score| score.player == bob).unwrap();
+        assert_eq!(bob_score.round_points, 1);
+    }
+
+    #[tokio::test]
+    async fn token_secret_is_shared() {
+        let Some(store) = store().await else {
+            return;
+        };
+        let secret = store.token_secret().await.unwrap();
+        let again = store.token_secret().await.unwrap();
+        assert_eq!(secret.expose_secret(), again.expose_secret());
+    }
+
+    #[tokio::test]
+    async fn every_key_keeps_expiring() {
+        let Some(store) = store().await else {
+            return;
+        };
+        let room = store.create_room().await.unwrap();
+        let ann = join(room.as_ref(), "Ann").await;
+        room.set_name_limit(3).await.unwrap();
+        room.set_duplicate_policy(DuplicatePolicy::Reject)
+            .await
+            .unwrap();
+        room.claim_prompt("Things you'd bring to a desert island")
+            .await
+            .unwrap();
+        add(room.as_ref(), "Ada", &ann).await;
+        room.change_state_to_playing(false).await.unwrap();
+        room.change_state_to_submitting(Some("Pets")).await.unwrap();
+        add(room.as_ref(), "Bea", &ann).await;
+
+        let keys = RoomKeys::new(room.code());
+        for key in keys.all() {
+            assert_ne!(ttl(&store, key).await, -1, "{key} never expires");
+        }
+    }
+
+    #[tokio::test]
+    async fn new_rooms_start_without_leftover_keys() {
+        let Some(store) = store().await else {
+            return;
+        };
+        let keys = RoomKeys::new(&RoomCode::generate());
+        let mut conn = store.conn.clone();
+        // what's left of a room whose state already expired
+        conn.hset(&keys.names, "id", "Ada").await.unwrap();
+
+        let create = async |conn: &mut ConnectionManager| -> bool {
+            let mut invocation = CREATE_ROOM_SCRIPT.prepare_invoke();
+            invocation.key(&keys.state).key(&keys.host_nonce);
+            for key in keys.all() {
+                invocation.key(key);
+            }
+            invocation
+                .arg(TTL.as_secs())
+                .arg(Uuid::new_v4())
+                .invoke_async(conn)
+                .await
+                .unwrap()
+        };
+        assert!(create(&mut conn).await);
+        assert_eq!(ttl(&store, &keys.names).await, -2);
+        assert!(ttl(&store, &keys.state).await > 0);
+        // the code is taken now
+        assert!(!create(&mut conn).await);
+    }
+
+    #[tokio::test]
+    async fn host_tokens_die_with_their_room() {
+        let Some(store) = store().await else {
+            return;
+        };
+        let tokens = TokenSigner::new(Some(SecretString::from("secret")));
+        let old = store.create_room().await.unwrap();
+        let token = tokens.issue_host(old.code(), &old.host_nonce().await.unwrap());
+
+        // the room expires, and its code comes up again for a new one
+        let keys = RoomKeys::new(old.code());
+        let mut conn = store.conn.clone();
+        conn.del(&keys.state).await.unwrap();
+        let mut invocation = CREATE_ROOM_SCRIPT.prepare_invoke();
+        invocation.key(&keys.state).key(&keys.host_nonce);
+        for key in keys.all() {
+            invocation.key(key);
+        }
+        let created: bool = invocation
+            .arg(TTL.as_secs())
+            .arg(Uuid::new_v4())
+            .invoke_async(&mut conn)
+            .await
+            .unwrap();
+        assert!(created);
+        let new = store.room(old.code().clone()).await.unwrap().unwrap();
+        let nonce = new.host_nonce().await.unwrap();
+        assert!(!tokens.verify_host(new.code(), &nonce, &token));
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::BTreeMap;
+
+use serde::{
+    Deserialize, Deserializer,
+    de::{Error, Visitor, value},
+    forward_to_deserialize_any,
+};
+use uuid::Uuid;
+
+use crate::{
+    Epoch, PlayerId, RoomCode,
+    messages::{Bitmap, Capability, ClientKind, ErrorCode, NameRef},
+    store::DuplicatePolicy,
+};
+
+/// TypeScript declarations of named types, by name.
+pub type Declarations = BTreeMap<&'static str, String>;
+
+/// A type that can appear in a message, as the frontend sees it once decoded.
+pub trait TsType {
+    /// How the type is written in TypeScript.
+    fn ts_type() -> String;
+
+    /// Adds the declarations of any named types `ts_type` refers to.
+    fn declare(_decls: &mut Declarations) {}
+}
+
+macro_rules! ts_alias {
+    ($($rust:ty => $ts:literal),+ $(,)?) => {
+        $(
+            impl TsType for $rust {
+                fn ts_type() -> String {
+                    $ts.to_string()
+                }
+            }
+        )+
+    };
+}
+
+ts_alias! {
+    bool => "boolean",
+    u8 => "number",
+    u32 => "number",
+    usize => "number",
+    String => "string",
+    Epoch => "number",
+    RoomCode => "string",
+    // the frontend unpacks bitmaps into one flag per name
+    Bitmap => "boolean[]",
+}
+
+impl TsType for Uuid {
+    fn ts_type() -> String {
+        "Uuid".to_string()
+    }
+
+    fn declare(decls: &mut Declarations) {
+        decls.insert("Uuid", "export type Uuid = string;".to_string());
+    }
+}
+
+impl TsType for PlayerId {
+    fn ts_type() -> String {
+        Uuid::ts_type()
+    }
+
+    fn declare(decls: &mut Declarations) {
+        Uuid::declare(decls);
+    }
+}
+
+impl<T: TsType> TsType for Option<T> {
+    fn ts_type() -> String {
+        format!("{} | null", T::ts_type())
+    }
+
+    fn declare(decls: &mut Declarations) {
+        T::declare(decls);
+    }
+}
+
+impl<T: TsType> TsType for Vec<T> {
+    fn ts_type() -> String {
+        let item = T::ts_type();
+        if item.contains('|') {
+            format!("({item})[]")
+        } else {
+            format!("{item}[]")
+        }
+    }
+
+    fn declare(decls: &mut Declarations) {
+        T::declare(decls);
+    }
+}
+
+impl<A: TsType, B: TsType> TsType for (A, B) {
+    fn ts_type() -> String {
+        format!("[{}, {}]", A::ts_type(), B::ts_type())
+    }
+
+    fn declare(decls: &mut Declarations) {
+        A::declare(decls);
+        B::declare(decls);
+    }
+}
+
+impl TsType for NameRef {
+    fn ts_type() -> String {
+        "NameRef".to_string()
+    }
+
+    fn declare(decls: &mut Declarations) {
+        Uuid::declare(decls);
+        decls.insert(
+            "NameRef",
+            "// by id while submitting, by index while playing\nexport type NameRef = Uuid | number;"
+                .to_string(),
+        );
+    }
+}
+
+/// Declares a labelled tuple, one element per line if it doesn't fit on one.
+pub fn tuple(name: &str, fields: &[(&str, String)]) -> String {
+    let elements: Vec<_> = fields
+        .iter()
+        .map(|(field, ty)| format!("{}: {ty}", camel_case(field)))
+        .collect();
+    let line = format!("export type {name} = [{}];", elements.join(", "));
+    if line.len() <= 80 {
+        return line;
+    }
+    let lines: String = elements
+        .iter()
+        .map(|element| format!("\n  {element},"))
+        .collect();
+    format!("export type {name} = [{lines}\n];")
+}
+
+/// Defines a struct that's sent as a tuple of its fields, declaring it in
+/// TypeScript from the same field list.
+macro_rules! ts_struct {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $($(#[$field_meta:meta])* $field_vis:vis $field:ident: $ty:ty),+ $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name {
+            $($(#[$field_meta])* $field_vis $field: $ty),+
+        }
+
+        impl $crate::schema::TsType for $name {
+            fn ts_type() -> String {
+                stringify!($name).to_string()
+            }
+
+            fn declare(decls: &mut $crate::schema::Declarations) {
+                $(<$ty as $crate::schema::TsType>::declare(decls);)+
+                let fields: &[(&str, String)] = &[
+                    $((stringify!($field), <$ty as $crate::schema::TsType>::ts_type())),+
+                ];
+                decls.insert(stringify!($name), $crate::schema::tuple(stringify!($name), fields));
+            }
+        }
+    };
+}
+
+pub(crate) use ts_struct;
+
+/// Deserializer that only records the variant names serde asks for, so that
+/// string enums are spelled in TypeScript exactly as they're sent.
+struct VariantNames<'a>(&'a mut &'static [&'static str]);
+
+impl<'de> Deserializer<'de> for VariantNames<'_> {
+    type Error = value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(Error::custom("not an enum"))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        variants: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = variants;
+        Err(Error::custom("only looking for the variant names"))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+/// Declares a union of string literals, on one line if it fits the way the
+/// frontend's formatter would put it.
+fn union(name: &str, variants: &[&str]) -> String {
+    let variants: Vec<_> = variants
+        .iter()
+        .map(|variant| format!("'{variant}'"))
+        .collect();
+    let line = format!("export type {name} = {};", variants.join(" | "));
+    if line.len() <= 80 {
+        return line;
+    }
+    let lines: String = variants
+        .iter()
+        .map(|variant| format!("\n  | {variant}"))
+        .collect();
+    format!("export type {name} ={lines};")
+}
+
+macro_rules! ts_string_enum {
+    ($($rust:ident),+ $(,)?) => {
+        $(
+            impl TsType for $rust {
+                fn ts_type() -> String {
+                    stringify!($rust).to_string()
+                }
+
+                fn declare(decls: &mut Declarations) {
+                    let mut variants: &[&str] = &[];
+                    let _ = $rust::deserialize(VariantNames(&mut variants));
+                    decls.insert(stringify!($rust), union(stringify!($rust), variants));
+                }
+            }
+        )+
+    };
+}
+
+ts_string_enum!(ErrorCode, DuplicatePolicy, ClientKind, Capability);
+
+/// Turns a Rust field name into the label of a TypeScript tuple element.
+pub fn camel_case(name: &str) -> String {
+    let mut parts = name.split('_');
+    let mut camel = parts.next().unwrap_or_default().to_string();
+    for part in parts {
+        let mut chars = part.chars();
+        if let Some(first) = chars.next() {
+            camel.extend(first.to_uppercase());
+            camel.push_str(chars.as_str());
+        }
+    }
+    camel
+}
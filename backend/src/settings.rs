@@ -1,14 +1,57 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use miette::IntoDiagnostic;
+use miette::{Context, IntoDiagnostic};
 use secrecy::SecretString;
+use serde::de::DeserializeOwned;
+
+use crate::{filter::FilterMode, limits::RateLimits, socket::Heartbeat, store::DuplicatePolicy};
+
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreKind {
+    #[default]
+    Redis,
+    Memory,
+}
 
 #[derive(serde::Deserialize, Clone, Debug)]
 pub struct Settings {
     pub host: String,
     pub port: u16,
+    #[serde(default)]
+    pub store: StoreKind,
     pub redis_url: SecretString,
+    /// How long a room is kept, in seconds, after the last sign of activity.
+    pub room_ttl_secs: u64,
+    /// Largest websocket message, in bytes, that clients may send.
+    pub max_message_size: usize,
+    /// Key for signing player and host tokens. If unset, the redis store
+    /// generates one and keeps it in Valkey, so that tokens survive restarts
+    /// like rooms do, and the memory store uses a random one.
+    pub token_secret: Option<SecretString>,
+    /// If set, only displays that know this secret can create rooms and
+    /// control them. Otherwise the display that creates a room is its host.
+    pub host_secret: Option<SecretString>,
+    /// How many names each player may submit per round, unless the host sets
+    /// a limit for the room. Unlimited if unset.
+    pub name_limit: Option<u32>,
+    /// How duplicate names are handled, unless the host sets a policy for
+    /// the room.
+    #[serde(default)]
+    pub duplicate_policy: DuplicatePolicy,
     pub serve_dir: Option<PathBuf>,
+    /// File with prompts the host can draw round themes from.
+    pub prompt_deck: Option<PathBuf>,
+    /// File with words that aren't allowed in names. Nothing is filtered if
+    /// unset.
+    pub content_filter: Option<PathBuf>,
+    /// What happens to names with a filtered word.
+    #[serde(default)]
+    pub content_filter_mode: FilterMode,
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+    #[serde(default)]
+    pub heartbeat: Heartbeat,
 }
 
 pub fn get_settings() -> miette::Result<Settings> {
@@ -29,5 +72,21 @@ pub fn get_settings() -> miette::Result<Settings> {
         .build()
         .into_diagnostic()?;
 
-    settings.try_deserialize().into_diagnostic()
+    let settings: Settings = settings.try_deserialize().into_diagnostic()?;
+    settings
+        .heartbeat
+        .validate()
+        .wrap_err("invalid heartbeat settings")?;
+    Ok(settings)
+}
+
+/// Reads a data file, such as a prompt deck, in any format the settings can
+/// be written in (e.g. TOML or JSON).
+pub fn load_file<T: DeserializeOwned>(path: &Path) -> miette::Result<T> {
+    config::Config::builder()
+        .add_source(config::File::from(path))
+        .build()
+        .and_then(config::Config::try_deserialize)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("load {}", path.display()))
 }
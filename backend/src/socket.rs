@@ -1,11 +1,132 @@
-use axum::extract::ws::{Message, WebSocket};
-use futures::{
-    SinkExt, StreamExt,
-    stream::{SplitSink, SplitStream},
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
 };
-use miette::{Context, IntoDiagnostic, bail};
 
-use crate::messages::NGMessage;
+use axum::extract::ws::{CloseFrame, Message, WebSocket, close_code};
+use bytes::Bytes;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use miette::{Context, ensure, miette};
+use tokio::time::{Instant, MissedTickBehavior, interval_at};
+use tokio_stream::wrappers::IntervalStream;
+use tracing::{debug, error, warn};
+use uuid::Uuid;
+
+use crate::{
+    limits::SocketLimits,
+    messages::{
+        Capability, ClientKind, ErrorCode, MIN_PROTOCOL_VERSION, NGMessage, PROTOCOL_VERSION,
+    },
+};
+
+/// How long a client has to introduce itself after connecting.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often the server checks that clients are still there.
+#[derive(serde::Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct Heartbeat {
+    /// Seconds between pings. A client that hasn't answered a ping by the
+    /// next one is considered unresponsive.
+    pub ping_interval_secs: u64,
+    /// Seconds a client may stay silent, pongs included, before its
+    /// connection is closed.
+    pub idle_timeout_secs: u64,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            ping_interval_secs: 15,
+            idle_timeout_secs: 45,
+        }
+    }
+}
+
+impl Heartbeat {
+    /// Checks that pings are sent at all, and that clients get to answer one
+    /// before they time out.
+    pub fn validate(&self) -> miette::Result<()> {
+        ensure!(
+            self.ping_interval_secs > 0,
+            "heartbeat.ping_interval_secs must be at least 1"
+        );
+        ensure!(
+            self.idle_timeout_secs > self.ping_interval_secs,
+            "heartbeat.idle_timeout_secs ({}) must be longer than heartbeat.ping_interval_secs ({})",
+            self.idle_timeout_secs,
+            self.ping_interval_secs,
+        );
+        Ok(())
+    }
+
+    fn ping_interval(&self) -> Duration {
+        Duration::from_secs(self.ping_interval_secs)
+    }
+
+    fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+}
+
+/// Whether a client answers the server's pings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Liveness {
+    Alive,
+    /// The client didn't answer the last ping, e.g. because the phone it's on
+    /// went to sleep, but may still come back before it times out.
+    Unresponsive,
+}
+
+/// Error that ends a single connection, without affecting any others.
+#[derive(Debug, thiserror::Error)]
+pub enum ConnectionError {
+    /// The client went away while we were talking to it.
+    #[error("connection to client was lost")]
+    Lost(#[source] axum::Error),
+    /// The client sent something that isn't a valid message.
+    #[error("client broke the protocol")]
+    Protocol(miette::Report),
+    /// The client sent a message of this type faster than it may. Only the
+    /// message is dropped, not the connection.
+    #[error("client is sending messages too fast")]
+    Throttled(u32),
+    /// The client kept sending messages too fast after being throttled.
+    #[error("client kept sending messages too fast")]
+    Flooding,
+    /// The client went silent for longer than the idle timeout, and is most
+    /// likely gone without saying so.
+    #[error("client stopped answering pings")]
+    TimedOut,
+    /// Something went wrong on our side, usually in the store.
+    #[error("internal error")]
+    Internal(miette::Report),
+}
+
+impl ConnectionError {
+    /// Close code to tell the client why the connection ended, if it's still
+    /// there to tell.
+    fn close_code(&self) -> Option<u16> {
+        match self {
+            ConnectionError::Lost(_) | ConnectionError::TimedOut => None,
+            ConnectionError::Protocol(_) => Some(close_code::PROTOCOL),
+            ConnectionError::Throttled(_) | ConnectionError::Flooding => Some(close_code::POLICY),
+            ConnectionError::Internal(_) => Some(close_code::ERROR),
+        }
+    }
+}
+
+impl From<miette::Report> for ConnectionError {
+    fn from(err: miette::Report) -> Self {
+        ConnectionError::Internal(err)
+    }
+}
+
+/// Frames on their way to the client.
+type FrameSink = Pin<Box<dyn Sink<Message, Error = axum::Error> + Send>>;
+/// Frames as they come in from the client.
+type FrameStream = Pin<Box<dyn Stream<Item = Result<Message, axum::Error>> + Send>>;
 
 pub struct Socket {
     sender: Sender,
@@ -13,20 +134,23 @@ pub struct Socket {
 }
 
 impl Socket {
-    pub fn new(socket: WebSocket) -> Self {
+    pub fn new(socket: WebSocket, limits: SocketLimits, heartbeat: Heartbeat) -> Self {
         let (sender, receiver) = socket.split();
-        let sender = Sender::new(sender);
-        let receiver = Receiver::new(receiver);
-        Self { sender, receiver }
-    }
-
-    pub async fn send(&mut self, message: NGMessage) -> miette::Result<()> {
-        self.sender.send(message).await
+        Self::from_parts(Box::pin(sender), Box::pin(receiver), limits, heartbeat)
     }
 
-    #[allow(dead_code)]
-    pub async fn recv(&mut self) -> miette::Result<Option<NGMessage>> {
-        self.receiver.recv().await
+    /// Wraps anything that carries websocket frames, so that handlers can be
+    /// driven without a real connection.
+    fn from_parts(
+        sender: FrameSink,
+        receiver: FrameStream,
+        limits: SocketLimits,
+        heartbeat: Heartbeat,
+    ) -> Self {
+        let last_seen = Arc::new(Mutex::new(Instant::now()));
+        let sender = Sender::new(sender, heartbeat, last_seen.clone());
+        let receiver = Receiver::new(receiver, limits, last_seen);
+        Self { sender, receiver }
     }
 
     pub fn split(self) -> (Sender, Receiver) {
@@ -35,44 +159,481 @@ impl Socket {
 }
 
 pub struct Sender {
-    sender: SplitSink<WebSocket, Message>,
+    sender: FrameSink,
+    /// Identifies the connection, e.g. for counting who's connected.
+    id: Uuid,
+    encoding: Encoding,
+    heartbeat: Heartbeat,
+    /// When the receiving half last got a frame.
+    last_seen: Arc<Mutex<Instant>>,
+    last_ping: Option<Instant>,
 }
 
 impl Sender {
-    fn new(sender: SplitSink<WebSocket, Message>) -> Self {
-        Self { sender }
+    fn new(sender: FrameSink, heartbeat: Heartbeat, last_seen: Arc<Mutex<Instant>>) -> Self {
+        Self {
+            sender,
+            id: Uuid::new_v4(),
+            encoding: Encoding::default(),
+            heartbeat,
+            last_seen,
+            last_ping: None,
+        }
     }
 
-    pub async fn send(&mut self, message: NGMessage) -> miette::Result<()> {
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// How long the client may stay silent before the connection is dropped.
+    pub fn idle_timeout(&self) -> Duration {
+        self.heartbeat.idle_timeout()
+    }
+
+    /// Ticks at which to call `heartbeat`.
+    pub fn heartbeats(&self) -> impl Stream<Item = ()> + use<> {
+        let period = self.heartbeat.ping_interval();
+        let mut interval = interval_at(Instant::now() + period, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        IntervalStream::new(interval).map(|_| ())
+    }
+
+    /// Pings the client, or gives up on it if it's been silent for too long.
+    /// Returns whether the client answered the last ping.
+    pub async fn heartbeat(&mut self) -> Result<Liveness, ConnectionError> {
+        let last_seen = *self.last_seen.lock().unwrap();
+        if last_seen.elapsed() >= self.heartbeat.idle_timeout() {
+            return Err(ConnectionError::TimedOut);
+        }
+        let liveness = match self.last_ping {
+            Some(last_ping) if last_seen < last_ping => Liveness::Unresponsive,
+            _ => Liveness::Alive,
+        };
         self.sender
-            .send(Message::Binary(message.encode()))
+            .send(Message::Ping(Bytes::new()))
             .await
-            .into_diagnostic()
+            .map_err(ConnectionError::Lost)?;
+        self.last_ping = Some(Instant::now());
+        Ok(liveness)
+    }
+
+    pub async fn send(&mut self, message: NGMessage) -> Result<(), ConnectionError> {
+        let frame = match self.encoding {
+            Encoding::MessagePack => Message::Binary(message.encode()),
+            Encoding::Json => Message::Text(message.encode_json().into()),
+        };
+        self.sender.send(frame).await.map_err(ConnectionError::Lost)
+    }
+
+    /// Ends the connection, telling the client why if `result` is an error.
+    pub async fn close(mut self, result: Result<(), ConnectionError>) {
+        let frame = match result {
+            Ok(()) => None,
+            Err(err) => {
+                match err {
+                    ConnectionError::Internal(_) => error!("closing connection: {err:?}"),
+                    _ => warn!("closing connection: {err:?}"),
+                }
+                let Some(code) = err.close_code() else {
+                    return;
+                };
+                Some(CloseFrame {
+                    code,
+                    reason: err.to_string().into(),
+                })
+            }
+        };
+        // the client may have gone away in the meantime, which is fine
+        let _ = self.sender.send(Message::Close(frame)).await;
     }
 }
 
 pub struct Receiver {
-    receiver: SplitStream<WebSocket>,
+    receiver: FrameStream,
+    limits: SocketLimits,
+    /// Messages throttled in a row.
+    strikes: u32,
+    /// Encoding of the first message, which the rest must use too.
+    encoding: Option<Encoding>,
+    last_seen: Arc<Mutex<Instant>>,
 }
 
 impl Receiver {
-    fn new(receiver: SplitStream<WebSocket>) -> Self {
-        Self { receiver }
-    }
-
-    pub async fn recv(&mut self) -> miette::Result<Option<NGMessage>> {
-        let bytes = match self.receiver.next().await {
-            Some(Ok(Message::Close(_))) | None => return Ok(None),
-            Some(Ok(Message::Binary(bytes))) => bytes,
-            Some(Ok(message)) => bail!("got non-binary message from client: {message:?}"),
-            Some(Err(err)) => {
-                return Err(err)
-                    .into_diagnostic()
-                    .wrap_err("got error while receiving message from client");
+    fn new(receiver: FrameStream, limits: SocketLimits, last_seen: Arc<Mutex<Instant>>) -> Self {
+        Self {
+            receiver,
+            limits,
+            strikes: 0,
+            encoding: None,
+            last_seen,
+        }
+    }
+
+    pub async fn recv(&mut self) -> Result<Option<NGMessage>, ConnectionError> {
+        let (encoding, message) = loop {
+            let frame = self.receiver.next().await;
+            if let Some(Ok(_)) = frame {
+                *self.last_seen.lock().unwrap() = Instant::now();
+            }
+            match frame {
+                Some(Ok(Message::Close(_))) | None => return Ok(None),
+                Some(Ok(Message::Binary(bytes))) => {
+                    break (Encoding::MessagePack, NGMessage::parse(bytes));
+                }
+                Some(Ok(Message::Text(text))) => {
+                    break (Encoding::Json, NGMessage::parse_json(&text));
+                }
+                // pings are answered for us, and pongs only show that the
+                // client is still there
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => {}
+                Some(Err(err)) => return Err(ConnectionError::Lost(err)),
+            }
+        };
+        let expected = *self.encoding.get_or_insert(encoding);
+        if encoding != expected {
+            return Err(ConnectionError::Protocol(miette!(
+                "client switched from {expected:?} to {encoding:?} frames"
+            )));
+        }
+        let message = message
+            .wrap_err("parse message from client")
+            .map_err(ConnectionError::Protocol)?;
+        if self.limits.allow() {
+            self.strikes = 0;
+            return Ok(Some(message));
+        }
+        self.strikes += 1;
+        if self.strikes > self.limits.max_strikes() {
+            return Err(ConnectionError::Flooding);
+        }
+        Err(ConnectionError::Throttled(message.type_id()))
+    }
+}
+
+fn outdated_client(in_reply_to: u32) -> NGMessage {
+    NGMessage::error(
+        ErrorCode::UnsupportedVersion,
+        "this page is out of date, please reload it",
+        in_reply_to,
+    )
+}
+
+/// How messages are put into frames. Clients pick one with the frame their
+/// `Hello` comes in, and stick to it for the whole connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// Binary frames, as the frontend sends them.
+    #[default]
+    MessagePack,
+    /// Text frames, for scripts and bots.
+    Json,
+}
+
+/// What a client and the server agreed on when it connected.
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub protocol_version: u32,
+    capabilities: Vec<Capability>,
+}
+
+impl Session {
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+}
+
+/// Waits for the client's `Hello` and answers with a `Welcome`. Clients newer
+/// than the server are downgraded to its version, and clients older than it
+/// still supports are told to reload before being disconnected.
+pub async fn handshake(
+    sender: &mut Sender,
+    receiver: &mut Receiver,
+    kind: ClientKind,
+) -> Result<Session, ConnectionError> {
+    let hello = tokio::time::timeout(HELLO_TIMEOUT, receiver.recv())
+        .await
+        .map_err(|_| ConnectionError::Protocol(miette!("client didn't say hello in time")))?;
+    let hello = hello?;
+    // answer in whatever the client spoke, even if it's about to be rejected
+    sender.encoding = receiver.encoding.unwrap_or_default();
+    let Some(msg) = hello else {
+        return Err(ConnectionError::Protocol(miette!(
+            "client left before saying hello"
+        )));
+    };
+    let msg_type = msg.type_id();
+    let (protocol_version, capabilities) = match msg {
+        NGMessage::Hello {
+            protocol_version,
+            client_kind,
+            capabilities,
+        } if client_kind == kind => (protocol_version, capabilities),
+        NGMessage::Hello { client_kind, .. } => {
+            return Err(ConnectionError::Protocol(miette!(
+                "{client_kind:?} client connected to the {kind:?} endpoint"
+            )));
+        }
+        // clients from before the handshake talk right away, so tell them to
+        // reload instead of just hanging up
+        msg => {
+            sender.send(outdated_client(msg_type)).await?;
+            return Err(ConnectionError::Protocol(miette!(
+                "client sent {msg:?} before saying hello"
+            )));
+        }
+    };
+    if protocol_version < MIN_PROTOCOL_VERSION {
+        sender.send(outdated_client(msg_type)).await?;
+        return Err(ConnectionError::Protocol(miette!(
+            "client speaks unsupported protocol version {protocol_version}"
+        )));
+    }
+    let session = Session {
+        protocol_version: protocol_version.min(PROTOCOL_VERSION),
+        // capabilities this server doesn't know are simply not granted
+        capabilities: capabilities
+            .iter()
+            .filter_map(|name| Capability::from_name(name))
+            .collect(),
+    };
+    debug!(
+        "client speaks protocol version {protocol_version}, using {}",
+        session.protocol_version
+    );
+    sender
+        .send(NGMessage::Welcome {
+            protocol_version: session.protocol_version,
+            capabilities: session.capabilities.clone(),
+        })
+        .await?;
+    Ok(session)
+}
+
+/// The client's end of a socket that never leaves the process, for driving
+/// handlers in tests.
+#[cfg(test)]
+pub struct TestClient {
+    frames_in: futures::channel::mpsc::UnboundedSender<Result<Message, axum::Error>>,
+    frames_out: futures::channel::mpsc::UnboundedReceiver<Message>,
+}
+
+#[cfg(test)]
+impl Socket {
+    /// Opens a socket with default limits whose other end is a `TestClient`.
+    pub fn in_process() -> (Self, TestClient) {
+        use std::net::{IpAddr, Ipv4Addr};
+
+        use crate::limits::{Limiter, RateLimits};
+
+        let (frames_in, receiver) = futures::channel::mpsc::unbounded();
+        let (sender, frames_out) = futures::channel::mpsc::unbounded();
+        let limits = Arc::new(Limiter::new(RateLimits::default()))
+            .connect(IpAddr::V4(Ipv4Addr::LOCALHOST))
+            .unwrap();
+        let socket = Self::from_parts(
+            Box::pin(sender.sink_map_err(axum::Error::new)),
+            Box::pin(receiver),
+            limits,
+            Heartbeat::default(),
+        );
+        (
+            socket,
+            TestClient {
+                frames_in,
+                frames_out,
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+impl TestClient {
+    pub fn send_frame(&self, frame: Message) {
+        self.frames_in.unbounded_send(Ok(frame)).unwrap();
+    }
+
+    pub fn send(&self, message: NGMessage) {
+        self.send_frame(Message::Binary(message.encode()));
+    }
+
+    /// Says hello the way the frontend would.
+    pub fn hello(&self, kind: ClientKind, capabilities: &[Capability]) {
+        self.send(NGMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_kind: kind,
+            capabilities: capabilities
+                .iter()
+                .map(|capability| serde_json::to_value(capability).unwrap())
+                .map(|name| name.as_str().unwrap().to_string())
+                .collect(),
+        });
+    }
+
+    /// Next frame from the server other than a ping, failing the test if
+    /// there's none within a second.
+    pub async fn recv_frame(&mut self) -> Option<Message> {
+        loop {
+            let frame = tokio::time::timeout(Duration::from_secs(1), self.frames_out.next())
+                .await
+                .expect("server didn't send anything in time");
+            if !matches!(frame, Some(Message::Ping(_))) {
+                return frame;
             }
+        }
+    }
+
+    /// Next message from the server, or `None` once it closes the connection.
+    pub async fn recv(&mut self) -> Option<NGMessage> {
+        match self.recv_frame().await? {
+            Message::Binary(bytes) => Some(NGMessage::parse(bytes).unwrap()),
+            Message::Text(text) => Some(NGMessage::parse_json(&text).unwrap()),
+            Message::Close(_) => None,
+            frame => panic!("unexpected frame from server: {frame:?}"),
+        }
+    }
+
+    /// Skips messages until `find` picks one out, failing the test if the
+    /// server closes the connection first.
+    pub async fn find<T>(&mut self, mut find: impl FnMut(NGMessage) -> Option<T>) -> T {
+        loop {
+            let message = self.recv().await.expect("server closed the connection");
+            if let Some(found) = find(message) {
+                return found;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the server's side of the handshake against whatever `client`
+    /// sends, closing the connection if it fails like the handlers do.
+    async fn shake(
+        kind: ClientKind,
+        client: impl FnOnce(&TestClient),
+    ) -> (Option<Session>, TestClient) {
+        let (socket, test_client) = Socket::in_process();
+        client(&test_client);
+        let (mut sender, mut receiver) = socket.split();
+        match handshake(&mut sender, &mut receiver, kind).await {
+            Ok(session) => (Some(session), test_client),
+            Err(err) => {
+                assert!(matches!(err, ConnectionError::Protocol(_)), "{err:?}");
+                sender.close(Err(err)).await;
+                (None, test_client)
+            }
+        }
+    }
+
+    fn hello(protocol_version: u32, client_kind: ClientKind, capabilities: &[&str]) -> NGMessage {
+        NGMessage::Hello {
+            protocol_version,
+            client_kind,
+            capabilities: capabilities.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn welcome_grants_only_known_capabilities() {
+        let (result, mut client) = shake(ClientKind::Display, |client| {
+            client.send(hello(
+                PROTOCOL_VERSION + 1,
+                ClientKind::Display,
+                &["presence", "telepathy"],
+            ));
+        })
+        .await;
+        let session = result.unwrap();
+        assert_eq!(session.protocol_version, PROTOCOL_VERSION);
+        assert!(session.supports(Capability::Presence));
+        assert!(!session.supports(Capability::Moderation));
+        let Some(NGMessage::Welcome {
+            protocol_version,
+            capabilities,
+        }) = client.recv().await
+        else {
+            panic!("server didn't welcome the client");
+        };
+        assert_eq!(protocol_version, PROTOCOL_VERSION);
+        assert_eq!(capabilities, [Capability::Presence]);
+    }
+
+    #[tokio::test]
+    async fn outdated_clients_are_told_to_reload() {
+        let (result, mut client) = shake(ClientKind::Player, |client| {
+            client.send(hello(MIN_PROTOCOL_VERSION - 1, ClientKind::Player, &[]));
+        })
+        .await;
+        assert!(result.is_none());
+        assert!(matches!(
+            client.recv().await,
+            Some(NGMessage::Error {
+                code: ErrorCode::UnsupportedVersion,
+                in_reply_to: Some(30),
+                ..
+            })
+        ));
+        assert!(client.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn clients_must_say_hello_first() {
+        let (result, mut client) = shake(ClientKind::Display, |client| {
+            client.send(NGMessage::RequestRevealState);
+        })
+        .await;
+        assert!(result.is_none());
+        assert!(matches!(
+            client.recv().await,
+            Some(NGMessage::Error {
+                code: ErrorCode::UnsupportedVersion,
+                in_reply_to: Some(14),
+                ..
+            })
+        ));
+        assert!(client.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn clients_must_use_their_own_endpoint() {
+        let (result, mut client) = shake(ClientKind::Display, |client| {
+            client.hello(ClientKind::Player, &[]);
+        })
+        .await;
+        assert!(result.is_none());
+        assert!(client.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn json_hellos_are_answered_in_json() {
+        let (result, mut client) = shake(ClientKind::Player, |client| {
+            client.send_frame(Message::Text(
+                r#"{"type":"Hello","content":[1,"player",["nameQuota"]]}"#.into(),
+            ));
+        })
+        .await;
+        assert!(result.unwrap().supports(Capability::NameQuota));
+        let Some(Message::Text(welcome)) = client.recv_frame().await else {
+            panic!("server didn't answer in JSON");
+        };
+        assert!(matches!(
+            NGMessage::parse_json(&welcome).unwrap(),
+            NGMessage::Welcome { .. }
+        ));
+    }
+
+    #[test]
+    fn heartbeat_needs_time_to_answer_a_ping() {
+        assert!(Heartbeat::default().validate().is_ok());
+        let heartbeat = |ping_interval_secs, idle_timeout_secs| Heartbeat {
+            ping_interval_secs,
+            idle_timeout_secs,
         };
-        Ok(Some(
-            NGMessage::parse(bytes).wrap_err("parse message from client")?,
-        ))
+        assert!(heartbeat(0, 45).validate().is_err());
+        assert!(heartbeat(15, 15).validate().is_err());
+        assert!(heartbeat(15, 10).validate().is_err());
+        assert!(heartbeat(15, 16).validate().is_ok());
     }
 }
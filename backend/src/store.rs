@@ -0,0 +1,467 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use futures::{StreamExt, stream::BoxStream};
+use tokio::sync::{broadcast::Sender as BroadcastSender, watch::Sender as WatchSender};
+use tokio_stream::wrappers::{BroadcastStream, WatchStream};
+use uuid::Uuid;
+
+use crate::{Epoch, GameState, Player, PlayerId, RoomCode, schema::ts_struct};
+
+ts_struct! {
+    #[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+    pub struct Score {
+        pub player: PlayerId,
+        pub nickname: String,
+        pub round_points: u32,
+        pub total_points: u32,
+    }
+}
+
+impl Score {
+    pub fn sort(scores: &mut [Score]) {
+        scores.sort_by(|a, b| {
+            b.total_points
+                .cmp(&a.total_points)
+                .then(b.round_points.cmp(&a.round_points))
+                .then_with(|| a.nickname.cmp(&b.nickname))
+        });
+    }
+}
+
+ts_struct! {
+    /// A player with a live connection to the room.
+    #[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+    pub struct RosterEntry {
+        pub player: PlayerId,
+        pub nickname: String,
+        /// Names the player submitted this round, while submitting.
+        pub names: u32,
+    }
+}
+
+/// What to do when a name is submitted that's already been submitted this
+/// round, ignoring case and accents.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicatePolicy {
+    /// Refuse the later submission.
+    Reject,
+    /// Show the name once when playing, credited to everyone who wrote it.
+    Merge,
+    /// Show every copy, but mark them as duplicates.
+    #[default]
+    Flag,
+}
+
+impl DuplicatePolicy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Reject => "reject",
+            Self::Merge => "merge",
+            Self::Flag => "flag",
+        }
+    }
+
+    pub fn parse(policy: &str) -> Option<Self> {
+        match policy {
+            "reject" => Some(Self::Reject),
+            "merge" => Some(Self::Merge),
+            "flag" => Some(Self::Flag),
+            _ => None,
+        }
+    }
+}
+
+/// Room settings that hosts can change for their own room.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RoomDefaults {
+    pub name_limit: Option<u32>,
+    pub duplicate_policy: DuplicatePolicy,
+}
+
+/// Outcome of submitting a name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddedName {
+    Added(Uuid),
+    /// The author already submitted as many names as they may.
+    LimitReached,
+    /// Somebody already submitted the same name, and duplicates are rejected.
+    Duplicate,
+}
+
+/// Outcome of a player taking back a name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RemovedName {
+    Removed,
+    /// Somebody else submitted the name.
+    NotAuthor,
+    NoSuchName,
+}
+
+/// Outcome of guessing a name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GuessedName {
+    Guessed,
+    /// The guesser wrote the name themselves, or is one of its authors if it
+    /// was merged.
+    OwnName,
+    /// Somebody already guessed the name, and points aren't reassigned.
+    AlreadyGuessed,
+}
+
+/// Random codes to try before giving up on creating a room, e.g. because
+/// nearly every code is taken.
+pub const CREATE_ROOM_ATTEMPTS: usize = 64;
+
+/// Creates and looks up rooms. Rooms nobody touched for a while are deleted.
+#[async_trait]
+pub trait RoomStore: Send + Sync {
+    async fn create_room(&self) -> miette::Result<Arc<dyn GameStore>>;
+
+    async fn room(&self, code: RoomCode) -> miette::Result<Option<Arc<dyn GameStore>>>;
+}
+
+/// State of a single room, shared by every connection to the room.
+#[async_trait]
+pub trait GameStore: Send + Sync {
+    fn code(&self) -> &RoomCode;
+
+    fn channels(&self) -> &RoomChannels;
+
+    /// Random value picked when the room was created, which host tokens are
+    /// tied to.
+    async fn host_nonce(&self) -> miette::Result<Uuid>;
+
+    /// Keeps the room from expiring for a while longer.
+    async fn touch(&self) -> miette::Result<()>;
+
+    async fn set_nickname(&self, player: &Player) -> miette::Result<()>;
+
+    async fn nickname(&self, player: &PlayerId) -> miette::Result<Option<String>>;
+
+    /// Counts `player` as connected through the connection `id` for `ttl`,
+    /// or until the connection is removed. Adding it again refreshes it, so
+    /// connections whose server went away expire on their own.
+    async fn add_connection(
+        &self,
+        id: &Uuid,
+        player: &PlayerId,
+        ttl: Duration,
+    ) -> miette::Result<()>;
+
+    /// Stops counting a connection. Does nothing if it isn't counted.
+    async fn remove_connection(&self, id: &Uuid) -> miette::Result<()>;
+
+    /// Players with a live connection to the room, by nickname.
+    async fn roster(&self) -> miette::Result<Vec<RosterEntry>>;
+
+    /// Adds a name unless `author` already submitted `limit` names this round,
+    /// or somebody submitted the same name and `reject_duplicates` is set. If
+    /// `hold` is set, the name waits for the host's approval.
+    async fn add_name(
+        &self,
+        name: &str,
+        author: &PlayerId,
+        limit: Option<u32>,
+        reject_duplicates: bool,
+        hold: bool,
+    ) -> miette::Result<AddedName>;
+
+    /// Removes a name submitted by `author`, unless somebody else submitted
+    /// it.
+    async fn remove_name(&self, id: &Uuid, author: &PlayerId) -> miette::Result<RemovedName>;
+
+    /// Names submitted this round that aren't waiting for approval, while
+    /// submitting.
+    async fn submitted_names(&self) -> miette::Result<Vec<(String, Uuid)>>;
+
+    /// Names waiting for the host's approval, oldest first.
+    async fn pending_names(&self) -> miette::Result<Vec<(String, Uuid)>>;
+
+    /// Lets a held name into the game. Returns `false` if it isn't waiting
+    /// for approval.
+    async fn approve_name(&self, id: &Uuid) -> miette::Result<bool>;
+
+    /// Removes a held name and tells its author. Returns `false` if it isn't
+    /// waiting for approval.
+    async fn reject_name(&self, id: &Uuid) -> miette::Result<bool>;
+
+    /// Removes any submitted name on the host's behalf and tells its author,
+    /// while submitting. Returns `false` if there's no such name.
+    async fn remove_name_as_host(&self, id: &Uuid) -> miette::Result<bool>;
+
+    /// Removes a shuffled name while playing, moving later names (and their
+    /// guesses) up by one and taking back any point given for it. Returns
+    /// `false` if there's no such name.
+    async fn remove_name_at(&self, index: usize) -> miette::Result<bool>;
+
+    /// Names submitted by `player` this round, while submitting.
+    async fn player_names(&self, player: &PlayerId) -> miette::Result<Vec<(String, Uuid)>>;
+
+    async fn names_and_guesses(&self) -> miette::Result<(Vec<String>, Vec<u8>)>;
+
+    /// Nicknames of the authors of the names, in the order the names are
+    /// shown while playing. Merged names list all their authors.
+    async fn authors(&self) -> miette::Result<Vec<String>>;
+
+    /// Marks a name as guessed, crediting `guesser` with a point if given.
+    /// If the name is already guessed, the point moves from whoever guessed it
+    /// when `reassign` is set, and nothing changes otherwise. Nothing changes
+    /// either if `guesser` wrote the name.
+    async fn guess_name(
+        &self,
+        index: usize,
+        guesser: Option<&PlayerId>,
+        reassign: bool,
+    ) -> miette::Result<GuessedName>;
+
+    async fn unguess_name(&self, index: usize) -> miette::Result<()>;
+
+    /// Every player who joined the room, best total score first.
+    async fn scoreboard(&self) -> miette::Result<Vec<Score>>;
+
+    /// What players should write names for this round, if the host chose
+    /// anything.
+    async fn theme(&self) -> miette::Result<Option<String>>;
+
+    /// How many names each player may submit per round, if the host set it
+    /// for this room. Zero means there's no limit.
+    async fn name_limit(&self) -> miette::Result<Option<u32>>;
+
+    /// Sets the room's name limit, telling every connection to the room.
+    async fn set_name_limit(&self, limit: u32) -> miette::Result<()>;
+
+    /// How duplicate names are handled, if the host set it for this room.
+    async fn duplicate_policy(&self) -> miette::Result<Option<DuplicatePolicy>>;
+
+    async fn set_duplicate_policy(&self, policy: DuplicatePolicy) -> miette::Result<()>;
+
+    /// Marks a prompt from the deck as used in this room. Returns `false` if
+    /// it already was.
+    async fn claim_prompt(&self, prompt: &str) -> miette::Result<bool>;
+
+    async fn change_state_to_submitting(&self, theme: Option<&str>) -> miette::Result<()>;
+
+    /// Shuffles the names for playing, merging names that are the same apart
+    /// from case and accents into one if `merge_duplicates` is set. Names
+    /// still waiting for approval are dropped.
+    async fn change_state_to_playing(&self, merge_duplicates: bool) -> miette::Result<()>;
+
+    async fn change_state_to_revealing(&self) -> miette::Result<()>;
+
+    /// How many names a player may submit per round, if limited.
+    async fn effective_name_limit(&self, defaults: &RoomDefaults) -> miette::Result<Option<u32>> {
+        Ok(match self.name_limit().await? {
+            Some(0) => None,
+            Some(limit) => Some(limit),
+            None => defaults.name_limit,
+        })
+    }
+
+    async fn effective_duplicate_policy(
+        &self,
+        defaults: &RoomDefaults,
+    ) -> miette::Result<DuplicatePolicy> {
+        Ok(self
+            .duplicate_policy()
+            .await?
+            .unwrap_or(defaults.duplicate_policy))
+    }
+
+    fn name_count(&self) -> usize {
+        *self.channels().num_names.borrow()
+    }
+
+    fn name_count_stream(&self) -> BoxStream<'static, usize> {
+        WatchStream::from_changes(self.channels().num_names.subscribe()).boxed()
+    }
+
+    fn guess_stream(&self) -> BoxStream<'static, usize> {
+        BroadcastStream::new(self.channels().guess.subscribe())
+            .filter_map(async |res| res.ok())
+            .boxed()
+    }
+
+    fn unguess_stream(&self) -> BoxStream<'static, usize> {
+        BroadcastStream::new(self.channels().unguess.subscribe())
+            .filter_map(async |res| res.ok())
+            .boxed()
+    }
+
+    fn scoreboard_stream(&self) -> BoxStream<'static, ()> {
+        WatchStream::from_changes(self.channels().scores.subscribe()).boxed()
+    }
+
+    /// Fires when names are held for approval or leave the queue.
+    fn pending_stream(&self) -> BoxStream<'static, ()> {
+        WatchStream::from_changes(self.channels().pending.subscribe()).boxed()
+    }
+
+    /// Names the host rejected or removed while submitting, with their
+    /// authors.
+    fn rejection_stream(&self) -> BoxStream<'static, (Uuid, PlayerId)> {
+        BroadcastStream::new(self.channels().rejected.subscribe())
+            .filter_map(async |res| res.ok())
+            .boxed()
+    }
+
+    /// Names submitted (or approved) while submitting, for the host's
+    /// preview.
+    fn submission_stream(&self) -> BoxStream<'static, (String, Uuid)> {
+        BroadcastStream::new(self.channels().submitted.subscribe())
+            .filter_map(async |res| res.ok())
+            .boxed()
+    }
+
+    /// Names removed while submitting, for the host's preview.
+    fn unsubmission_stream(&self) -> BoxStream<'static, Uuid> {
+        BroadcastStream::new(self.channels().unsubmitted.subscribe())
+            .filter_map(async |res| res.ok())
+            .boxed()
+    }
+
+    /// Fires when players connect, disconnect, stop answering or expire.
+    fn presence_stream(&self) -> BoxStream<'static, ()> {
+        WatchStream::from_changes(self.channels().presence.subscribe()).boxed()
+    }
+
+    /// Fires when the shuffled names change other than by being guessed.
+    fn names_changed_stream(&self) -> BoxStream<'static, ()> {
+        WatchStream::from_changes(self.channels().names_changed.subscribe()).boxed()
+    }
+
+    /// Fires when the host changes the room's name limit.
+    fn name_limit_stream(&self) -> BoxStream<'static, ()> {
+        WatchStream::from_changes(self.channels().name_limit.subscribe()).boxed()
+    }
+
+    /// Fires when the room's state had to be read again from scratch.
+    fn resync_stream(&self) -> BoxStream<'static, ()> {
+        WatchStream::from_changes(self.channels().resync.subscribe()).boxed()
+    }
+
+    fn state(&self) -> GameState {
+        *self.channels().state_change.borrow()
+    }
+
+    fn state_change_stream(&self) -> BoxStream<'static, GameState> {
+        WatchStream::from_changes(self.channels().state_change.subscribe()).boxed()
+    }
+}
+
+/// In-process fan-out of a room's events to every connection to the room.
+#[derive(Debug)]
+pub struct RoomChannels {
+    num_names: WatchSender<usize>,
+    guess: BroadcastSender<usize>,
+    unguess: BroadcastSender<usize>,
+    scores: WatchSender<()>,
+    pending: WatchSender<()>,
+    rejected: BroadcastSender<(Uuid, PlayerId)>,
+    submitted: BroadcastSender<(String, Uuid)>,
+    unsubmitted: BroadcastSender<Uuid>,
+    names_changed: WatchSender<()>,
+    presence: WatchSender<()>,
+    name_limit: WatchSender<()>,
+    state_change: WatchSender<GameState>,
+    resync: WatchSender<()>,
+}
+
+impl RoomChannels {
+    pub fn new(num_names: usize, game_state: GameState) -> Self {
+        Self {
+            num_names: WatchSender::new(num_names),
+            guess: BroadcastSender::new(128),
+            unguess: BroadcastSender::new(128),
+            scores: WatchSender::new(()),
+            pending: WatchSender::new(()),
+            rejected: BroadcastSender::new(128),
+            submitted: BroadcastSender::new(128),
+            unsubmitted: BroadcastSender::new(128),
+            names_changed: WatchSender::new(()),
+            presence: WatchSender::new(()),
+            name_limit: WatchSender::new(()),
+            state_change: WatchSender::new(game_state),
+            resync: WatchSender::new(()),
+        }
+    }
+
+    pub fn set_name_count(&self, num_names: usize) {
+        self.num_names.send_replace(num_names);
+    }
+
+    pub fn publish_guess(&self, index: usize) {
+        // an error only means nobody is listening right now
+        let _ = self.guess.send(index);
+    }
+
+    pub fn publish_unguess(&self, index: usize) {
+        let _ = self.unguess.send(index);
+    }
+
+    pub fn publish_scores_changed(&self) {
+        self.scores.send_replace(());
+    }
+
+    pub fn publish_pending_changed(&self) {
+        self.pending.send_replace(());
+    }
+
+    pub fn publish_rejection(&self, id: Uuid, author: PlayerId) {
+        let _ = self.rejected.send((id, author));
+    }
+
+    pub fn publish_submitted(&self, name: String, id: Uuid) {
+        let _ = self.submitted.send((name, id));
+    }
+
+    pub fn publish_unsubmitted(&self, id: Uuid) {
+        let _ = self.unsubmitted.send(id);
+    }
+
+    pub fn publish_names_changed(&self) {
+        self.names_changed.send_replace(());
+    }
+
+    pub fn publish_presence_changed(&self) {
+        self.presence.send_replace(());
+    }
+
+    pub fn publish_name_limit_changed(&self) {
+        self.name_limit.send_replace(());
+    }
+
+    pub fn enter_submitting(&self, epoch: Epoch) {
+        // update number of names without sending a notification (no
+        // notification is needed, as any currently connected displays will
+        // get a 0 num names packet when the state change is observed)
+        self.num_names.send_if_modified(|num| {
+            *num = 0;
+            false
+        });
+        self.state_change.send_replace(GameState::Submitting(epoch));
+    }
+
+    pub fn enter_playing(&self) {
+        self.state_change.send_replace(GameState::Playing);
+    }
+
+    pub fn enter_revealing(&self) {
+        self.state_change.send_replace(GameState::Revealing);
+    }
+
+    /// Replaces the room's state after events may have been missed, and has
+    /// every connection send a fresh snapshot instead of individual changes.
+    pub fn resync(&self, num_names: usize, game_state: GameState) {
+        self.num_names.send_if_modified(|num| {
+            *num = num_names;
+            false
+        });
+        self.state_change.send_if_modified(|state| {
+            *state = game_state;
+            false
+        });
+        self.resync.send_replace(());
+    }
+}
@@ -0,0 +1,152 @@
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use rand::{RngCore, rng};
+use secrecy::{ExposeSecret, SecretString};
+use sha2::Sha256;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{PlayerId, RoomCode};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Issues and checks the tokens players use to keep their identity across
+/// reconnects. A token is the player's id followed by a MAC over the room code
+/// and the id, so it is only valid in the room it was issued for.
+///
+/// Host tokens, which let a display control its room, are just a MAC over the
+/// room code, a fixed label and the room's host nonce. The nonce is new for
+/// every room, so tokens don't carry over to a later room with the same code.
+#[derive(Clone)]
+pub struct TokenSigner {
+    mac: HmacSha256,
+}
+
+impl TokenSigner {
+    const ID_LEN: usize = 16;
+    const MAC_LEN: usize = 16;
+    const HOST_LABEL: &'static [u8] = b"host";
+
+    pub fn new(secret: Option<SecretString>) -> Self {
+        let mac = match secret {
+            Some(secret) => HmacSha256::new_from_slice(secret.expose_secret().as_bytes()),
+            None => {
+                warn!("no token secret configured, player tokens won't survive a restart");
+                let mut key = [0; 32];
+                rng().fill_bytes(&mut key);
+                HmacSha256::new_from_slice(&key)
+            }
+        }
+        .expect("HMAC accepts keys of any length");
+        Self { mac }
+    }
+
+    fn sign(&self, room: &RoomCode, subject: &[u8]) -> HmacSha256 {
+        let mut mac = self.mac.clone();
+        mac.update(room.to_string().as_bytes());
+        mac.update(subject);
+        mac
+    }
+
+    pub fn issue(&self, room: &RoomCode, player: &PlayerId) -> String {
+        let tag = self.sign(room, player.0.as_bytes()).finalize().into_bytes();
+        let mut token = Vec::with_capacity(Self::ID_LEN + Self::MAC_LEN);
+        token.extend_from_slice(player.0.as_bytes());
+        token.extend_from_slice(&tag[..Self::MAC_LEN]);
+        URL_SAFE_NO_PAD.encode(token)
+    }
+
+    pub fn verify(&self, room: &RoomCode, token: &str) -> Option<PlayerId> {
+        let token = URL_SAFE_NO_PAD.decode(token).ok()?;
+        if token.len() != Self::ID_LEN + Self::MAC_LEN {
+            return None;
+        }
+        let (id, tag) = token.split_at(Self::ID_LEN);
+        let player = PlayerId(Uuid::from_slice(id).ok()?);
+        self.sign(room, player.0.as_bytes())
+            .verify_truncated_left(tag)
+            .ok()?;
+        Some(player)
+    }
+
+    fn sign_host(&self, room: &RoomCode, nonce: &Uuid) -> HmacSha256 {
+        let mut mac = self.sign(room, Self::HOST_LABEL);
+        mac.update(nonce.as_bytes());
+        mac
+    }
+
+    pub fn issue_host(&self, room: &RoomCode, nonce: &Uuid) -> String {
+        let tag = self.sign_host(room, nonce).finalize().into_bytes();
+        URL_SAFE_NO_PAD.encode(&tag[..Self::MAC_LEN])
+    }
+
+    pub fn verify_host(&self, room: &RoomCode, nonce: &Uuid, token: &str) -> bool {
+        let Ok(tag) = URL_SAFE_NO_PAD.decode(token) else {
+            return false;
+        };
+        tag.len() == Self::MAC_LEN
+            && self
+                .sign_host(room, nonce)
+                .verify_truncated_left(&tag)
+                .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(code: &str) -> RoomCode {
+        RoomCode::try_from(code.to_string()).unwrap()
+    }
+
+    fn signer(secret: &str) -> TokenSigner {
+        TokenSigner::new(Some(SecretString::from(secret)))
+    }
+
+    #[test]
+    fn player_tokens_round_trip() {
+        let tokens = signer("secret");
+        let player = PlayerId::new();
+        let token = tokens.issue(&code("BCDF"), &player);
+        assert_eq!(tokens.verify(&code("BCDF"), &token), Some(player));
+        // the same secret works after a restart
+        assert_eq!(signer("secret").verify(&code("BCDF"), &token), Some(player));
+    }
+
+    #[test]
+    fn player_tokens_only_work_where_issued() {
+        let tokens = signer("secret");
+        let token = tokens.issue(&code("BCDF"), &PlayerId::new());
+        assert_eq!(tokens.verify(&code("BCDG"), &token), None);
+        assert_eq!(signer("other").verify(&code("BCDF"), &token), None);
+    }
+
+    #[test]
+    fn tampered_player_tokens_are_rejected() {
+        let tokens = signer("secret");
+        let token = tokens.issue(&code("BCDF"), &PlayerId::new());
+        // swap in somebody else's id, keeping the MAC
+        let mut bytes = URL_SAFE_NO_PAD.decode(&token).unwrap();
+        bytes[..TokenSigner::ID_LEN].copy_from_slice(PlayerId::new().0.as_bytes());
+        let forged = URL_SAFE_NO_PAD.encode(bytes);
+        assert_eq!(tokens.verify(&code("BCDF"), &forged), None);
+        assert_eq!(tokens.verify(&code("BCDF"), &token[1..]), None);
+        assert_eq!(tokens.verify(&code("BCDF"), "not a token!"), None);
+    }
+
+    #[test]
+    fn host_tokens_only_work_where_issued() {
+        let tokens = signer("secret");
+        let nonce = Uuid::new_v4();
+        let token = tokens.issue_host(&code("BCDF"), &nonce);
+        assert!(tokens.verify_host(&code("BCDF"), &nonce, &token));
+        assert!(!tokens.verify_host(&code("BCDG"), &nonce, &token));
+        assert!(!signer("other").verify_host(&code("BCDF"), &nonce, &token));
+        // a later room with the same code has another nonce
+        assert!(!tokens.verify_host(&code("BCDF"), &Uuid::new_v4(), &token));
+        // player tokens don't make anyone a host
+        let player_token = tokens.issue(&code("BCDF"), &PlayerId::new());
+        assert!(!tokens.verify_host(&code("BCDF"), &nonce, &player_token));
+    }
+}
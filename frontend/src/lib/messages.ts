@@ -1,103 +1,8 @@
 import { decode, encode } from '@msgpack/msgpack';
 import { parse, stringify } from 'uuid';
+import { MessageType, type Message } from './schema';
 
-export enum MessageType {
-  StateSubmitting,
-  SubmitName,
-  NameSubmitted,
-  UnsubmitName,
-  NameUnsubmitted,
-  NumNames,
-  RequestPlayingState,
-  Names,
-  GuessName,
-  NameGuessed,
-  UnguessName,
-  NameUnguessed,
-  RequestSubmittingState,
-}
-
-export type Uuid = string;
-
-export type StateSubmittingMessage = {
-  type: MessageType.StateSubmitting;
-  content: number;
-};
-
-export type SubmitNameMessage = {
-  type: MessageType.SubmitName;
-  content: string;
-};
-
-export type NameSubmittedMessage = {
-  type: MessageType.NameSubmitted;
-  content: [string, Uuid];
-};
-
-export type UnsubmitNameMessage = {
-  type: MessageType.UnsubmitName;
-  content: Uuid;
-};
-
-export type NameUnsubmittedMessage = {
-  type: MessageType.NameUnsubmitted;
-  content: Uuid;
-};
-
-export type NumNamesMessage = {
-  type: MessageType.NumNames;
-  content: number;
-};
-
-export type RequestPlayingStateMessage = {
-  type: MessageType.RequestPlayingState;
-  content: null;
-};
-
-export type NamesMessage = {
-  type: MessageType.Names;
-  content: [string[], boolean[]];
-};
-
-export type GuessNameMessage = {
-  type: MessageType.GuessName;
-  content: number;
-};
-
-export type NameGuessedMessage = {
-  type: MessageType.NameGuessed;
-  content: number;
-};
-
-export type UnguessNameMessage = {
-  type: MessageType.UnguessName;
-  content: number;
-};
-
-export type NameUnguessedMessage = {
-  type: MessageType.NameUnguessed;
-  content: number;
-};
-
-export type RequestSubmittingStateMessage = {
-  type: MessageType.RequestSubmittingState;
-  content: null;
-};
-
-export type Message =
-  | StateSubmittingMessage
-  | SubmitNameMessage
-  | NameSubmittedMessage
-  | UnsubmitNameMessage
-  | NameUnsubmittedMessage
-  | NumNamesMessage
-  | RequestPlayingStateMessage
-  | NamesMessage
-  | GuessNameMessage
-  | NameGuessedMessage
-  | UnguessNameMessage
-  | NameUnguessedMessage
-  | RequestSubmittingStateMessage;
+export * from './schema';
 
 function bitfieldToBooleanArray(
   bitfield: Uint8Array,
@@ -141,12 +46,14 @@ export function decodeMessage(message: ArrayBuffer): Message {
   let content = decode(new Uint8Array(message, 4)) as Message['content'];
   switch (type) {
     case MessageType.Names: {
-      const [names, guessesBitfield] = content as unknown as [
-        string[],
-        Uint8Array,
-      ];
+      const [names, guessesBitfield, duplicatesBitfield] =
+        content as unknown as [string[], Uint8Array, Uint8Array];
       const guesses = bitfieldToBooleanArray(guessesBitfield, names.length);
-      content = [names, guesses];
+      const duplicates = bitfieldToBooleanArray(
+        duplicatesBitfield,
+        names.length,
+      );
+      content = [names, guesses, duplicates];
       break;
     }
     case MessageType.NameSubmitted: {
@@ -156,8 +63,37 @@ export function decodeMessage(message: ArrayBuffer): Message {
     }
     case MessageType.UnsubmitName:
     case MessageType.NameUnsubmitted:
+    case MessageType.ApproveName:
+    case MessageType.RejectName:
+    case MessageType.NameHeld:
+    case MessageType.NameApproved:
       content = stringify(content as unknown as Uint8Array);
       break;
+    case MessageType.RemoveName:
+      if (content instanceof Uint8Array) {
+        content = stringify(content);
+      }
+      break;
+    case MessageType.PendingNames: {
+      const pending = content as unknown as [string, Uint8Array][];
+      content = pending.map(([name, id]) => [name, stringify(id)]);
+      break;
+    }
+    case MessageType.GuessNameBy: {
+      const [index, player] = content as unknown as [number, Uint8Array];
+      content = [index, stringify(player)];
+      break;
+    }
+    case MessageType.Scoreboard: {
+      const scores = content as unknown as [Uint8Array, string, number, number][];
+      content = scores.map(([player, ...rest]) => [stringify(player), ...rest]);
+      break;
+    }
+    case MessageType.Roster: {
+      const roster = content as unknown as [Uint8Array, string, number][];
+      content = roster.map(([player, ...rest]) => [stringify(player), ...rest]);
+      break;
+    }
   }
 
   return { type, content };
@@ -178,6 +114,7 @@ export function encodeMessage(message: Message): ArrayBuffer {
       content = encode([
         message.content[0],
         booleanArrayToBitfield(message.content[1]),
+        booleanArrayToBitfield(message.content[2]),
       ]);
       break;
     case MessageType.NameSubmitted:
@@ -185,19 +122,64 @@ export function encodeMessage(message: Message): ArrayBuffer {
       break;
     case MessageType.UnsubmitName:
     case MessageType.NameUnsubmitted:
+    case MessageType.ApproveName:
+    case MessageType.RejectName:
+    case MessageType.NameHeld:
+    case MessageType.NameApproved:
       content = encode(parse(message.content));
       break;
+    case MessageType.RemoveName:
+      content = encode(
+        typeof message.content === 'string'
+          ? parse(message.content)
+          : message.content,
+      );
+      break;
+    case MessageType.PendingNames:
+      content = encode(
+        message.content.map(([name, id]) => [name, parse(id)]),
+      );
+      break;
+    case MessageType.GuessNameBy:
+      content = encode([message.content[0], parse(message.content[1])]);
+      break;
+    case MessageType.Scoreboard:
+      content = encode(
+        message.content.map(([player, ...rest]) => [parse(player), ...rest]),
+      );
+      break;
+    case MessageType.Roster:
+      content = encode(
+        message.content.map(([player, ...rest]) => [parse(player), ...rest]),
+      );
+      break;
     case MessageType.StateSubmitting:
     case MessageType.SubmitName:
     case MessageType.NumNames:
+    case MessageType.ConnectedPlayers:
     case MessageType.GuessName:
     case MessageType.NameGuessed:
     case MessageType.UnguessName:
     case MessageType.NameUnguessed:
+    case MessageType.JoinCode:
+    case MessageType.Authors:
+    case MessageType.PlayerToken:
+    case MessageType.Error:
+    case MessageType.HostToken:
+    case MessageType.SetNameLimit:
+    case MessageType.NameQuota:
+    case MessageType.SetDuplicatePolicy:
+    case MessageType.SetPreview:
+    case MessageType.Hello:
+    case MessageType.Welcome:
       content = encode(message.content);
       break;
     case MessageType.RequestSubmittingState:
+      content = message.content === null ? null : encode(message.content);
+      break;
     case MessageType.RequestPlayingState:
+    case MessageType.RequestRevealState:
+    case MessageType.DrawPrompt:
       content = null;
       break;
   }
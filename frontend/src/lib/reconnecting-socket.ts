@@ -1,12 +1,25 @@
-import { decodeMessage, encodeMessage, type Message } from './messages';
+import {
+  decodeMessage,
+  encodeMessage,
+  MessageType,
+  PROTOCOL_VERSION,
+  type Capability,
+  type ClientKind,
+  type Message,
+} from './messages';
 
 export type OpenHandler = (() => void) | null;
 export type MessageHandler = ((message: Message) => void) | null;
 export type CloseHandler = (() => void) | null;
+// evaluated on every (re)connection, so the url can carry state the server
+// handed out on an earlier connection
+export type SocketUrl = string | URL | (() => string | URL);
 
 export class ReconnectingSocket {
   private ws: WebSocket | null = null;
-  private url: string | URL;
+  private url: SocketUrl;
+  private kind: ClientKind;
+  private capabilities: Capability[];
 
   private _onOpen: OpenHandler = null;
   private _onMessage: MessageHandler = null;
@@ -14,11 +27,16 @@ export class ReconnectingSocket {
 
   private attempt = 0;
   private reconnectTimeout: number | null = null;
+  private closed = false;
 
-  private MAX_ATTEMPTS = 10;
-
-  constructor(url: string | URL) {
+  constructor(
+    url: SocketUrl,
+    kind: ClientKind,
+    capabilities: Capability[] = [],
+  ) {
     this.url = url;
+    this.kind = kind;
+    this.capabilities = capabilities;
     this.connect();
     document.addEventListener('visibilitychange', () => {
       if (document.visibilityState === 'hidden') {
@@ -29,7 +47,7 @@ export class ReconnectingSocket {
           clearTimeout(this.reconnectTimeout);
           this.reconnectTimeout = null;
         }
-      } else {
+      } else if (!this.closed) {
         this.connect();
       }
     });
@@ -60,9 +78,16 @@ export class ReconnectingSocket {
   }
 
   private connect() {
-    this.ws = new WebSocket(this.url);
+    this.ws = new WebSocket(
+      typeof this.url === 'function' ? this.url() : this.url,
+    );
     this.ws.binaryType = 'arraybuffer';
     this.ws.addEventListener('open', () => {
+      // the server doesn't talk to us until we introduce ourselves
+      this.send({
+        type: MessageType.Hello,
+        content: [PROTOCOL_VERSION, this.kind, this.capabilities],
+      });
       this._onOpen?.();
       this.attempt = 0;
     });
@@ -73,7 +98,7 @@ export class ReconnectingSocket {
     this.ws.addEventListener('close', () => {
       this.ws = null;
       this._onClose?.();
-      if (document.visibilityState === 'visible') {
+      if (!this.closed && document.visibilityState === 'visible') {
         console.warn('WebSocket closed. Reconnecting...');
         this.reconnect();
       }
@@ -84,18 +109,19 @@ export class ReconnectingSocket {
     });
   }
 
+  /** Closes the socket for good, without reconnecting. */
   public close() {
-    if (this.connected) {
-      this.ws!.close();
+    this.closed = true;
+    if (this.reconnectTimeout !== null) {
+      clearTimeout(this.reconnectTimeout);
+      this.reconnectTimeout = null;
     }
+    this.ws?.close();
   }
 
+  // keeps trying for as long as the socket is open, since the server may
+  // just be restarting; the delay stops growing once it reaches the maximum
   private reconnect() {
-    if (this.attempt >= this.MAX_ATTEMPTS) {
-      console.error('Max reconnection attempts reached');
-      return;
-    }
-
     if (this.reconnectTimeout === null) {
       const delay = this.getBackoffDelay();
       console.log(`Reconnecting in ${delay} ms...`);
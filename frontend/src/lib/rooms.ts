@@ -0,0 +1,12 @@
+/**
+ * Whether the server says the room doesn't exist, as opposed to the server
+ * being unreachable for a moment, e.g. while it restarts.
+ */
+export async function isRoomGone(code: string): Promise<boolean> {
+  try {
+    const response = await fetch(`/api/rooms/${encodeURIComponent(code)}`);
+    return response.status === 404;
+  } catch {
+    return false;
+  }
+}
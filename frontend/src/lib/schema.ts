@@ -0,0 +1,346 @@
+// Generated by `backend --emit-ts-schema`, don't edit by hand.
+
+export const PROTOCOL_VERSION = 1;
+
+export enum MessageType {
+  StateSubmitting = 0,
+  SubmitName = 1,
+  NameSubmitted = 2,
+  UnsubmitName = 3,
+  NameUnsubmitted = 4,
+  NumNames = 5,
+  RequestPlayingState = 6,
+  Names = 7,
+  GuessName = 8,
+  NameGuessed = 9,
+  UnguessName = 10,
+  NameUnguessed = 11,
+  RequestSubmittingState = 12,
+  JoinCode = 13,
+  RequestRevealState = 14,
+  Authors = 15,
+  GuessNameBy = 16,
+  Scoreboard = 17,
+  PlayerToken = 18,
+  Error = 19,
+  HostToken = 20,
+  DrawPrompt = 21,
+  SetNameLimit = 22,
+  NameQuota = 23,
+  SetDuplicatePolicy = 24,
+  PendingNames = 25,
+  ApproveName = 26,
+  RejectName = 27,
+  RemoveName = 28,
+  SetPreview = 29,
+  Hello = 30,
+  Welcome = 31,
+  ConnectedPlayers = 32,
+  Roster = 33,
+  NameHeld = 34,
+  NameApproved = 35,
+}
+
+export type Capability = 'nameQuota' | 'moderation' | 'presence';
+
+export type ClientKind = 'player' | 'display';
+
+export type DuplicatePolicy = 'reject' | 'merge' | 'flag';
+
+export type ErrorCode =
+  | 'wrongState'
+  | 'invalidIndex'
+  | 'emptyName'
+  | 'nameTooLong'
+  | 'invalidCharacters'
+  | 'themeTooLong'
+  | 'noPromptsLeft'
+  | 'nameLimitReached'
+  | 'duplicateName'
+  | 'inappropriateName'
+  | 'namesPending'
+  | 'noSuchName'
+  | 'rateLimited'
+  | 'unauthorized'
+  | 'unexpectedMessage'
+  | 'unsupportedVersion'
+  | 'alreadyGuessed';
+
+// by id while submitting, by index while playing
+export type NameRef = Uuid | number;
+
+export type RosterEntry = [player: Uuid, nickname: string, names: number];
+
+export type Score = [
+  player: Uuid,
+  nickname: string,
+  roundPoints: number,
+  totalPoints: number,
+];
+
+export type Uuid = string;
+
+export type StateSubmittingMessage = {
+  type: MessageType.StateSubmitting;
+  content: [epoch: number, theme: string | null];
+};
+
+export type SubmitNameMessage = {
+  type: MessageType.SubmitName;
+  content: string;
+};
+
+export type NameSubmittedMessage = {
+  type: MessageType.NameSubmitted;
+  content: [name: string, id: Uuid];
+};
+
+export type UnsubmitNameMessage = {
+  type: MessageType.UnsubmitName;
+  content: Uuid;
+};
+
+export type NameUnsubmittedMessage = {
+  type: MessageType.NameUnsubmitted;
+  content: Uuid;
+};
+
+export type NumNamesMessage = {
+  type: MessageType.NumNames;
+  content: number;
+};
+
+export type RequestPlayingStateMessage = {
+  type: MessageType.RequestPlayingState;
+  content: null;
+};
+
+/**
+ * Names with bitmaps of which are guessed and which are duplicates.
+ */
+export type NamesMessage = {
+  type: MessageType.Names;
+  content: [names: string[], guesses: boolean[], duplicates: boolean[]];
+};
+
+export type GuessNameMessage = {
+  type: MessageType.GuessName;
+  content: number;
+};
+
+export type NameGuessedMessage = {
+  type: MessageType.NameGuessed;
+  content: number;
+};
+
+export type UnguessNameMessage = {
+  type: MessageType.UnguessName;
+  content: number;
+};
+
+export type NameUnguessedMessage = {
+  type: MessageType.NameUnguessed;
+  content: number;
+};
+
+/**
+ * Starts a new round, optionally with a theme for the names.
+ */
+export type RequestSubmittingStateMessage = {
+  type: MessageType.RequestSubmittingState;
+  content: string | null;
+};
+
+export type JoinCodeMessage = {
+  type: MessageType.JoinCode;
+  content: string;
+};
+
+export type RequestRevealStateMessage = {
+  type: MessageType.RequestRevealState;
+  content: null;
+};
+
+export type AuthorsMessage = {
+  type: MessageType.Authors;
+  content: string[];
+};
+
+export type GuessNameByMessage = {
+  type: MessageType.GuessNameBy;
+  content: [index: number, player: Uuid];
+};
+
+export type ScoreboardMessage = {
+  type: MessageType.Scoreboard;
+  content: Score[];
+};
+
+export type PlayerTokenMessage = {
+  type: MessageType.PlayerToken;
+  content: string;
+};
+
+export type ErrorMessage = {
+  type: MessageType.Error;
+  content: [code: ErrorCode, message: string, inReplyTo: number | null];
+};
+
+export type HostTokenMessage = {
+  type: MessageType.HostToken;
+  content: string;
+};
+
+/**
+ * Starts a new round themed with a prompt from the deck.
+ */
+export type DrawPromptMessage = {
+  type: MessageType.DrawPrompt;
+  content: null;
+};
+
+/**
+ * Sets how many names each player may submit per round, zero meaning
+ * no limit.
+ */
+export type SetNameLimitMessage = {
+  type: MessageType.SetNameLimit;
+  content: number;
+};
+
+/**
+ * How many more names the player may submit this round, if limited.
+ */
+export type NameQuotaMessage = {
+  type: MessageType.NameQuota;
+  content: number | null;
+};
+
+export type SetDuplicatePolicyMessage = {
+  type: MessageType.SetDuplicatePolicy;
+  content: DuplicatePolicy;
+};
+
+/**
+ * Names waiting for the host's approval, sent to the host display only.
+ */
+export type PendingNamesMessage = {
+  type: MessageType.PendingNames;
+  content: [string, Uuid][];
+};
+
+export type ApproveNameMessage = {
+  type: MessageType.ApproveName;
+  content: Uuid;
+};
+
+export type RejectNameMessage = {
+  type: MessageType.RejectName;
+  content: Uuid;
+};
+
+export type RemoveNameMessage = {
+  type: MessageType.RemoveName;
+  content: NameRef;
+};
+
+/**
+ * Turns the host display's preview of submitted names on or off.
+ */
+export type SetPreviewMessage = {
+  type: MessageType.SetPreview;
+  content: boolean;
+};
+
+/**
+ * First message of every connection. Capabilities are plain strings, so
+ * that newer clients can ask for features this server doesn't know.
+ */
+export type HelloMessage = {
+  type: MessageType.Hello;
+  content: [
+    protocolVersion: number,
+    clientKind: ClientKind,
+    capabilities: string[],
+  ];
+};
+
+/**
+ * Reply to `Hello`, with the version and capabilities both sides share.
+ */
+export type WelcomeMessage = {
+  type: MessageType.Welcome;
+  content: [protocolVersion: number, capabilities: Capability[]];
+};
+
+/**
+ * How many players are connected and answering, sent to displays.
+ */
+export type ConnectedPlayersMessage = {
+  type: MessageType.ConnectedPlayers;
+  content: number;
+};
+
+/**
+ * Who's connected, sent to displays whenever someone joins, leaves or
+ * submits a name.
+ */
+export type RosterMessage = {
+  type: MessageType.Roster;
+  content: RosterEntry[];
+};
+
+/**
+ * The player's name is waiting for the host's approval, and is left out
+ * of the game unless approved.
+ */
+export type NameHeldMessage = {
+  type: MessageType.NameHeld;
+  content: Uuid;
+};
+
+/**
+ * The host approved the player's held name.
+ */
+export type NameApprovedMessage = {
+  type: MessageType.NameApproved;
+  content: Uuid;
+};
+
+export type Message =
+  | StateSubmittingMessage
+  | SubmitNameMessage
+  | NameSubmittedMessage
+  | UnsubmitNameMessage
+  | NameUnsubmittedMessage
+  | NumNamesMessage
+  | RequestPlayingStateMessage
+  | NamesMessage
+  | GuessNameMessage
+  | NameGuessedMessage
+  | UnguessNameMessage
+  | NameUnguessedMessage
+  | RequestSubmittingStateMessage
+  | JoinCodeMessage
+  | RequestRevealStateMessage
+  | AuthorsMessage
+  | GuessNameByMessage
+  | ScoreboardMessage
+  | PlayerTokenMessage
+  | ErrorMessage
+  | HostTokenMessage
+  | DrawPromptMessage
+  | SetNameLimitMessage
+  | NameQuotaMessage
+  | SetDuplicatePolicyMessage
+  | PendingNamesMessage
+  | ApproveNameMessage
+  | RejectNameMessage
+  | RemoveNameMessage
+  | SetPreviewMessage
+  | HelloMessage
+  | WelcomeMessage
+  | ConnectedPlayersMessage
+  | RosterMessage
+  | NameHeldMessage
+  | NameApprovedMessage;
@@ -1,27 +1,54 @@
-import type { Uuid } from './messages';
+const ROOM_KEY = 'room';
+const HOST_ROOM_KEY = 'hostRoom';
+const PLAYER_TOKEN_KEY = 'playerToken';
 
-const NAMES_KEY = 'names';
+export type PlayerRoom = { code: string; nickname: string };
 
-export function getNames(curEpoch: number): [string, Uuid][] {
-  const json = window.sessionStorage.getItem(NAMES_KEY);
-  if (json === null) {
-    return [];
-  }
+export function getRoom(): PlayerRoom | null {
+  const json = window.sessionStorage.getItem(ROOM_KEY);
+  return json === null ? null : (JSON.parse(json) as PlayerRoom);
+}
 
-  const [storedEpoch, names] = JSON.parse(json) as [number, [string, Uuid][]];
-  if (curEpoch === storedEpoch) {
-    return names;
-  } else {
-    window.sessionStorage.removeItem(NAMES_KEY);
-    return [];
-  }
+export function setRoom(room: PlayerRoom) {
+  window.sessionStorage.setItem(ROOM_KEY, JSON.stringify(room));
+}
+
+export function clearRoom() {
+  window.sessionStorage.removeItem(ROOM_KEY);
+}
+
+export type HostRoom = { code: string; token: string };
+
+/** The room this display created, so that reconnecting keeps hosting it. */
+export function getHostRoom(): HostRoom | null {
+  const json = window.sessionStorage.getItem(HOST_ROOM_KEY);
+  return json === null ? null : (JSON.parse(json) as HostRoom);
+}
+
+export function setHostRoom(room: HostRoom) {
+  window.sessionStorage.setItem(HOST_ROOM_KEY, JSON.stringify(room));
+}
+
+export function clearHostRoom() {
+  window.sessionStorage.removeItem(HOST_ROOM_KEY);
 }
 
-export function setNames(epoch: number, names: [string, Uuid][]) {
-  const json = JSON.stringify([epoch, names]);
-  window.sessionStorage.setItem(NAMES_KEY, json);
+type PlayerToken = { code: string; token: string };
+
+/**
+ * The token the player was given in the room, so that they keep their names
+ * and points when they come back, even from another tab.
+ */
+export function getPlayerToken(code: string): string | null {
+  const json = window.localStorage.getItem(PLAYER_TOKEN_KEY);
+  if (json === null) {
+    return null;
+  }
+  const stored = JSON.parse(json) as PlayerToken;
+  return stored.code === code ? stored.token : null;
 }
 
-export function clearNames() {
-  window.sessionStorage.removeItem(NAMES_KEY);
+export function setPlayerToken(code: string, token: string) {
+  const json = JSON.stringify({ code, token } satisfies PlayerToken);
+  window.localStorage.setItem(PLAYER_TOKEN_KEY, json);
 }
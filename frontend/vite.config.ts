@@ -7,6 +7,10 @@ export default defineConfig({
   plugins: [tailwindcss(), svelte()],
   server: {
     proxy: {
+      '/api': {
+        target: 'http://localhost:8080/',
+        changeOrigin: true,
+      },
       '/ws': {
         target: 'ws://localhost:8080/',
         changeOrigin: true,